@@ -3,38 +3,53 @@
 
 use std::io::Cursor;
 
-use crate::encoding::{from_image, FromImageOptions};
-use crate::{db, util};
+use crate::encoding::{self, from_image, FromImageOptions};
+use crate::{db, limits, util};
 use bson::Document;
 use futures::join;
 use futures::stream::TryStreamExt;
 use image::io::Reader;
 use mongodb::bson::doc;
+use mongodb::options::FindOptions;
 use mongodb::Collection;
 use tokio::task;
 use util::ImageId;
 
-/// Optimize an image from the database and bump its compression level.
-pub async fn optimize_image_and_update(
+/// The optimization level images end up at, they aren't re-encoded after it
+pub const FINAL_OPTIM_LEVEL: i64 = 1;
+
+/// Optimize the image with the id and bump its compression level. Waits
+/// until we're not under memory or upload pressure before loading the image,
+/// since re-encoding is the heaviest thing we do and waiting images shouldn't
+/// hold on to memory.
+pub async fn optimize_image_by_id(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+) -> Result<(), String> {
+    limits::wait_for_capacity().await;
+    let image_doc = db::get_image(images_collection, &image_id.0)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Image was deleted before it was optimized")?;
+    optimize_image_and_update(images_collection, &image_doc).await
+}
+
+/// Optimize an image from the database and bump its compression level
+async fn optimize_image_and_update(
     images_collection: &Collection<Document>,
     image_doc: &Document,
 ) -> Result<(), String> {
     let image_id = ImageId(
         image_doc
             .get_str("_id")
-            .expect("Image id must be a string")
+            .map_err(|_| "Image id must be a string")?
             .to_string(),
     );
-    let image_bytes = image_doc
-        .get_binary_generic("data")
-        .expect("data must be set")
-        .clone();
-    let content_type = image_doc
-        .get_str("content_type")
-        .expect("content_type must be set");
-    let optimization_level = image_doc
-        .get_i32("optim_level")
-        .expect("optim_level must be set") as u8;
+    let (image_bytes, content_type) = util::get_stored_data(image_doc, "data", "content_type")
+        .map_err(|e| format!("{:?} data", e))?;
+    let content_type = content_type.as_str();
+    let optimization_level =
+        util::get_int(image_doc, "optim_level").ok_or("optim_level must be set")? as u8;
 
     // create a DynamicImage from the bytes and content type
     let mut read_image = Reader::new(Cursor::new(image_bytes));
@@ -62,7 +77,7 @@ pub async fn optimize_image_and_update(
         image,
         FromImageOptions {
             optimize_png: true,
-            max_size: Some(128),
+            max_size: Some(encoding::THUMBNAIL_SIZE),
             ..FromImageOptions::default()
         },
     );
@@ -90,6 +105,10 @@ pub async fn optimize_image_and_update(
             size: encoded_image.size,
 
             optim_level: optimization_level + 1,
+
+            // ignored since the image already exists
+            settings: &db::ImageSettings::default(),
+            quality: None,
         },
     )
     .await
@@ -103,37 +122,40 @@ pub async fn optimize_images_from_database(
     images_collection: &Collection<Document>,
 ) -> Result<(), String> {
     println!("optimize_images_from_database");
-    // delete images that haven't been viewed in a year
+    // delete images that haven't been viewed in a year, unless they're pinned
     let target_datetime =
         bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() - 31_536_000_000);
     images_collection
         .delete_many(
             doc! {
                 "last_seen": {"$lt": target_datetime},
+                "pinned": {"$ne": true},
             },
             None,
         )
         .await
         .map_err(|e| e.to_string())?;
 
-    // images with an optimization level of 0
+    // ids of images with an optimization level of 0, each image is only
+    // loaded once there's capacity to optimize it
     let mut images_cursor = images_collection
         .find(
             doc! {
                 "optim_level": 0
             },
-            None,
+            FindOptions::builder().projection(doc! {"_id": 1}).build(),
         )
         .await
         .map_err(|e| e.to_string())?;
     while let Some(im) = images_cursor.try_next().await.map_err(|e| e.to_string())? {
+        let image_id = ImageId(im.get_str("_id").unwrap_or_default().to_string());
         // if there's an error, just ignore it
-        optimize_image_and_update(images_collection, &im)
+        optimize_image_by_id(images_collection, &image_id)
             .await
             .unwrap_or_else(|e| {
                 println!("Error optimizing image: {}", e);
             });
-        info!("optimized image {}", im.get_str("_id").unwrap());
+        info!("optimized image {}", image_id);
     }
     info!("Done optimizing images.");
 
@@ -0,0 +1,371 @@
+//! Drives a mix of uploads, metadata reads and image downloads against a
+//! running instance and reports latency percentiles for each.
+//!
+//! ```text
+//! cargo run --release --bin loadtest -- http://localhost:8000 \
+//!     --concurrency 16 --requests 2000 --uploads 1 --metadata 3 --images 16
+//! ```
+//!
+//! All requests come from one IP, so with a concurrency above the instance's
+//! MAX_CONCURRENT_UPLOADS_PER_IP (default 4) some uploads fail with 429 Too
+//! Many Requests unless that's raised.
+
+use base64::{engine::general_purpose, Engine as _};
+use image::{DynamicImage, Rgb, RgbImage};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rocket::serde::json::json;
+use rocket::serde::Deserialize;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many images are uploaded before the test starts, so there's something
+/// to read from the start
+const SEED_IMAGES: usize = 10;
+/// How many ids are asked for in each metadata batch
+const METADATA_BATCH_SIZE: usize = 10;
+
+const USAGE: &str = "usage: loadtest <base url> [--concurrency N] [--requests N] \
+                     [--uploads WEIGHT] [--metadata WEIGHT] [--images WEIGHT]";
+
+#[derive(Debug)]
+struct Config {
+    base_url: String,
+    concurrency: usize,
+    requests: usize,
+    upload_weight: u32,
+    metadata_weight: u32,
+    image_weight: u32,
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config {
+            base_url: args.next().ok_or(USAGE)?.trim_end_matches('/').to_string(),
+            // MAX_CONCURRENT_UPLOADS_PER_IP's default
+            concurrency: 4,
+            requests: 1000,
+            upload_weight: 1,
+            metadata_weight: 3,
+            image_weight: 16,
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            let parse_error = |_| format!("{} isn't a number: {}", flag, value);
+            match flag.as_str() {
+                "--concurrency" => config.concurrency = value.parse().map_err(parse_error)?,
+                "--requests" => config.requests = value.parse().map_err(parse_error)?,
+                "--uploads" => config.upload_weight = value.parse().map_err(parse_error)?,
+                "--metadata" => config.metadata_weight = value.parse().map_err(parse_error)?,
+                "--images" => config.image_weight = value.parse().map_err(parse_error)?,
+                _ => return Err(format!("unknown flag {}\n{}", flag, USAGE)),
+            }
+        }
+        if config.concurrency == 0 {
+            return Err("--concurrency must be at least 1".to_string());
+        }
+        if [
+            config.upload_weight,
+            config.metadata_weight,
+            config.image_weight,
+        ] == [0; 3]
+        {
+            return Err("at least one of the weights must be more than 0".to_string());
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    Upload,
+    Metadata,
+    Image,
+}
+
+impl Operation {
+    const ALL: [Operation; 3] = [Operation::Upload, Operation::Metadata, Operation::Image];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Upload => "upload",
+            Operation::Metadata => "metadata",
+            Operation::Image => "image",
+        }
+    }
+
+    fn pick(config: &Config, rng: &mut impl Rng) -> Self {
+        let weights = [
+            config.upload_weight,
+            config.metadata_weight,
+            config.image_weight,
+        ];
+        // summed as u64 so big weights can't overflow
+        let mut roll = rng.gen_range(0..weights.iter().map(|w| u64::from(*w)).sum::<u64>());
+        for (operation, weight) in Self::ALL.into_iter().zip(weights) {
+            if roll < u64::from(weight) {
+                return operation;
+            }
+            roll -= u64::from(weight);
+        }
+        unreachable!("the roll is less than the sum of the weights")
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    data: UploadData,
+}
+
+#[derive(Deserialize)]
+struct UploadData {
+    id: String,
+}
+
+/// Everything the workers share
+struct State {
+    config: Config,
+    client: reqwest::Client,
+    /// The body of every upload, a base64 png in JSON
+    upload_body: String,
+    /// Ids of images that have been uploaded
+    ids: Mutex<Vec<String>>,
+    /// How many requests have been started
+    started: AtomicUsize,
+    /// The latency of each successful request, and the number of failures,
+    /// for each operation
+    results: Mutex<Vec<(Operation, Result<Duration, String>)>>,
+}
+
+/// A noisy png, so it's not trivially compressible like real photos
+fn test_image_png() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let image = DynamicImage::ImageRgb8(RgbImage::from_fn(512, 384, |x, y| {
+        let noise: u8 = rng.gen_range(0..32);
+        Rgb([
+            (x / 2) as u8 ^ noise,
+            (y * 2 / 3) as u8 ^ noise,
+            ((x + y) / 4) as u8,
+        ])
+    }));
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageOutputFormat::Png)
+        .expect("encoding a png in memory can't fail");
+    png.into_inner()
+}
+
+async fn upload(state: &State) -> Result<(), String> {
+    let response = state
+        .client
+        .post(format!("{}/api/upload", state.config.base_url))
+        .header("Content-Type", "application/json")
+        .body(state.upload_body.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let uploaded: UploadResponse = response.json().await.map_err(|e| e.to_string())?;
+    state.ids.lock().unwrap().push(uploaded.data.id);
+    Ok(())
+}
+
+async fn read_metadata(state: &State) -> Result<(), String> {
+    let ids: Vec<String> = {
+        let ids = state.ids.lock().unwrap();
+        ids.choose_multiple(&mut rand::thread_rng(), METADATA_BATCH_SIZE)
+            .cloned()
+            .collect()
+    };
+    state
+        .client
+        .post(format!(
+            "{}/api/images/metadata/batch",
+            state.config.base_url
+        ))
+        .json(&json!({ "ids": ids }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn download_image(state: &State) -> Result<(), String> {
+    let (id, thumbnail) = {
+        let ids = state.ids.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        (ids.choose(&mut rng).cloned(), rng.gen_bool(0.5))
+    };
+    let id = id.ok_or("no images to download")?;
+    let path = if thumbnail {
+        format!("/i/{}/thumb", id)
+    } else {
+        format!("/i/{}", id)
+    };
+    state
+        .client
+        .get(format!("{}{}", state.config.base_url, path))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn worker(state: Arc<State>) {
+    while state.started.fetch_add(1, Ordering::Relaxed) < state.config.requests {
+        let operation = Operation::pick(&state.config, &mut rand::thread_rng());
+        let started = Instant::now();
+        let result = match operation {
+            Operation::Upload => upload(&state).await,
+            Operation::Metadata => read_metadata(&state).await,
+            Operation::Image => download_image(&state).await,
+        };
+        let result = result.map(|_| started.elapsed());
+        state.results.lock().unwrap().push((operation, result));
+    }
+}
+
+/// The value below which `percentile` percent of the sorted latencies fall
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn report(results: &[(Operation, Result<Duration, String>)], elapsed: Duration) {
+    println!(
+        "{} requests in {:.1}s ({:.1} req/s)",
+        results.len(),
+        elapsed.as_secs_f64(),
+        results.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{:<10} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9}",
+        "operation", "ok", "failed", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for operation in Operation::ALL {
+        let mut latencies = Vec::new();
+        let mut failures = Vec::new();
+        for (_, result) in results.iter().filter(|(op, _)| *op == operation) {
+            match result {
+                Ok(latency) => latencies.push(*latency),
+                Err(e) => failures.push(e),
+            }
+        }
+        latencies.sort();
+        let millis = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        println!(
+            "{:<10} {:>7} {:>7} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+            operation.name(),
+            latencies.len(),
+            failures.len(),
+            millis(percentile(&latencies, 50.0)),
+            millis(percentile(&latencies, 90.0)),
+            millis(percentile(&latencies, 99.0)),
+            millis(latencies.last().copied().unwrap_or_default()),
+        );
+        if let Some(e) = failures.first() {
+            println!("  first failure: {}", e);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let upload_body = json!({
+        "base64": general_purpose::STANDARD.encode(test_image_png()),
+    })
+    .to_string();
+    let state = Arc::new(State {
+        config,
+        client: reqwest::Client::new(),
+        upload_body,
+        ids: Mutex::new(Vec::new()),
+        started: AtomicUsize::new(0),
+        results: Mutex::new(Vec::new()),
+    });
+
+    println!("Uploading {} seed images", SEED_IMAGES);
+    for _ in 0..SEED_IMAGES {
+        if let Err(e) = upload(&state).await {
+            eprintln!("Failed to upload a seed image: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    println!("Running {:?}", state.config);
+    let started = Instant::now();
+    let workers: Vec<_> = (0..state.config.concurrency)
+        .map(|_| tokio::spawn(worker(state.clone())))
+        .collect();
+    for worker in workers {
+        worker.await.expect("worker panicked");
+    }
+    report(&state.results.lock().unwrap(), started.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn percentile_works() {
+        let latencies: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+    #[test]
+    fn config_from_args_works() {
+        let args = [
+            "http://localhost:8000/",
+            "--concurrency",
+            "4",
+            "--uploads",
+            "0",
+        ];
+        let config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(config.base_url, "http://localhost:8000");
+        assert_eq!(config.concurrency, 4);
+        assert_eq!(config.upload_weight, 0);
+        assert!(Config::from_args(std::iter::empty()).is_err());
+    }
+    #[test]
+    fn pick_handles_big_weights() {
+        let args = [
+            "http://localhost:8000",
+            "--uploads",
+            "0",
+            "--metadata",
+            "4294967295",
+            "--images",
+            "4294967295",
+        ];
+        let config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            assert_ne!(Operation::pick(&config, &mut rng), Operation::Upload);
+        }
+    }
+}
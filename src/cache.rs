@@ -0,0 +1,121 @@
+//! How long browsers and CDNs are allowed to cache images.
+
+use std::fmt;
+
+lazy_static! {
+    /// The policy for images that weren't uploaded with one
+    pub static ref DEFAULT_CACHE_POLICY: CachePolicy = std::env::var("DEFAULT_CACHE_POLICY")
+        .ok()
+        .and_then(|policy| CachePolicy::parse(&policy))
+        .unwrap_or(CachePolicy::MaxAge(86_400));
+}
+
+/// How long "immutable" images are cached for, a year like browsers expect
+const IMMUTABLE_MAX_AGE: u32 = 31_536_000;
+
+/// Chosen by the uploader with the `cache` field, stored as a string like
+/// "revalidate", "immutable" or a number of seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+    /// Caches have to check with us every time, so changes show up right away
+    Revalidate,
+    /// Cache for this many seconds
+    MaxAge(u32),
+    /// Cache for as long as possible without checking again
+    Immutable,
+}
+
+impl CachePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "revalidate" => Some(CachePolicy::Revalidate),
+            "immutable" => Some(CachePolicy::Immutable),
+            seconds => seconds.parse().ok().map(CachePolicy::MaxAge),
+        }
+    }
+
+    /// The policy to actually send for an image. Re-encoding in the
+    /// background changes the bytes served at the same URL, so images are only
+    /// immutable once they're at their final optimization level and have to be
+    /// revalidated until then.
+    pub fn for_image(self, is_final: bool) -> Self {
+        match self {
+            CachePolicy::Immutable if !is_final => CachePolicy::Revalidate,
+            policy => policy,
+        }
+    }
+
+    /// The Cache-Control header value, shared caches like CDNs aren't
+    /// allowed to store private images
+    pub fn header_value(self, private: bool) -> String {
+        let scope = if private { "private" } else { "public" };
+        match self {
+            CachePolicy::Revalidate => format!("{}, no-cache", scope),
+            CachePolicy::MaxAge(seconds) => format!("{}, max-age={}", scope, seconds),
+            CachePolicy::Immutable => {
+                format!("{}, max-age={}, immutable", scope, IMMUTABLE_MAX_AGE)
+            }
+        }
+    }
+}
+
+impl fmt::Display for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CachePolicy::Revalidate => write!(f, "revalidate"),
+            CachePolicy::MaxAge(seconds) => write!(f, "{}", seconds),
+            CachePolicy::Immutable => write!(f, "immutable"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_roundtrips() {
+        for policy in [
+            CachePolicy::Revalidate,
+            CachePolicy::MaxAge(3600),
+            CachePolicy::Immutable,
+        ] {
+            assert_eq!(CachePolicy::parse(&policy.to_string()), Some(policy));
+        }
+        assert_eq!(
+            CachePolicy::parse(" Immutable "),
+            Some(CachePolicy::Immutable)
+        );
+        assert_eq!(CachePolicy::parse("-1"), None);
+        assert_eq!(CachePolicy::parse("forever"), None);
+    }
+    #[test]
+    fn for_image_holds_back_immutable() {
+        assert_eq!(
+            CachePolicy::Immutable.for_image(false),
+            CachePolicy::Revalidate
+        );
+        assert_eq!(
+            CachePolicy::Immutable.for_image(true),
+            CachePolicy::Immutable
+        );
+        assert_eq!(
+            CachePolicy::MaxAge(60).for_image(false),
+            CachePolicy::MaxAge(60)
+        );
+    }
+    #[test]
+    fn header_value_works() {
+        assert_eq!(
+            CachePolicy::MaxAge(60).header_value(false),
+            "public, max-age=60"
+        );
+        assert_eq!(
+            CachePolicy::Revalidate.header_value(true),
+            "private, no-cache"
+        );
+        assert_eq!(
+            CachePolicy::Immutable.header_value(false),
+            "public, max-age=31536000, immutable"
+        );
+    }
+}
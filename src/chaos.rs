@@ -0,0 +1,140 @@
+//! Fault injection for checking how clients and background jobs cope with a
+//! slow or failing server. Only enabled with CHAOS_MODE, and refuses to start
+//! with Rocket's release profile.
+
+use rand::Rng;
+use rocket::data::Data;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::{Build, Request, Response, Rocket};
+use std::io::Cursor;
+use std::time::Duration;
+
+lazy_static! {
+    static ref CHAOS: Option<ChaosConfig> = ChaosConfig::from_env();
+}
+
+const INJECTED_ERROR_BODY: &str = r#"{"error":"Injected failure","success":false,"status":500}"#;
+
+#[derive(Debug)]
+struct ChaosConfig {
+    /// The chance of a request being delayed by up to `max_latency`
+    latency_probability: f64,
+    max_latency: Duration,
+    /// The chance of a request failing with 500 Internal Server Error
+    error_probability: f64,
+    /// The chance of a database operation failing
+    storage_failure_probability: f64,
+}
+
+impl ChaosConfig {
+    fn from_env() -> Option<Self> {
+        let enabled = std::env::var("CHAOS_MODE")
+            .map(|enabled| crate::util::parse_bool_field(&enabled))
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+        let probability =
+            |name: &str| parse_probability(&std::env::var(name).unwrap_or_default()).unwrap_or(0.0);
+        let config = ChaosConfig {
+            latency_probability: probability("CHAOS_LATENCY_PROBABILITY"),
+            max_latency: Duration::from_millis(
+                std::env::var("CHAOS_MAX_LATENCY_MS")
+                    .ok()
+                    .and_then(|millis| millis.parse().ok())
+                    .unwrap_or(1000),
+            ),
+            error_probability: probability("CHAOS_ERROR_PROBABILITY"),
+            storage_failure_probability: probability("CHAOS_STORAGE_FAILURE_PROBABILITY"),
+        };
+        warn!("Chaos mode is enabled: {:?}", config);
+        Some(config)
+    }
+}
+
+/// Parse a probability, clamped to between 0 and 1
+pub fn parse_probability(value: &str) -> Option<f64> {
+    let probability: f64 = value.trim().parse().ok()?;
+    if probability.is_nan() {
+        return None;
+    }
+    Some(probability.clamp(0.0, 1.0))
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::thread_rng().gen_bool(probability)
+}
+
+/// Fail a database operation if chaos mode decides to
+pub fn storage_fault() -> Result<(), mongodb::error::Error> {
+    match CHAOS.as_ref() {
+        Some(chaos) if roll(chaos.storage_failure_probability) => Err(
+            mongodb::error::Error::custom("Injected storage failure".to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the current request was picked to fail
+struct InjectedError(bool);
+
+/// Delays and fails requests at random when CHAOS_MODE is enabled
+pub struct Chaos;
+
+#[rocket::async_trait]
+impl Fairing for Chaos {
+    fn info(&self) -> Info {
+        Info {
+            name: "Chaos",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        if CHAOS.is_some() && rocket.figment().profile() == rocket::Config::RELEASE_PROFILE {
+            error!("CHAOS_MODE can't be enabled with the release profile");
+            return Err(rocket);
+        }
+        Ok(rocket)
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let chaos = match CHAOS.as_ref() {
+            Some(chaos) => chaos,
+            None => return,
+        };
+        if roll(chaos.latency_probability) {
+            let millis = chaos.max_latency.as_millis() as u64;
+            let delay = rand::thread_rng().gen_range(0..=millis);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        req.local_cache(|| InjectedError(roll(chaos.error_probability)));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if req.local_cache(|| InjectedError(false)).0 {
+            res.set_status(Status::InternalServerError);
+            res.set_header(ContentType::JSON);
+            res.set_sized_body(INJECTED_ERROR_BODY.len(), Cursor::new(INJECTED_ERROR_BODY));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_probability_clamps() {
+        assert_eq!(parse_probability("0.25"), Some(0.25));
+        assert_eq!(parse_probability("2"), Some(1.0));
+        assert_eq!(parse_probability("-1"), Some(0.0));
+        assert_eq!(parse_probability("NaN"), None);
+        assert_eq!(parse_probability(""), None);
+    }
+    #[test]
+    fn roll_respects_extremes() {
+        assert!(!roll(0.0));
+        assert!(roll(1.0));
+    }
+}
@@ -0,0 +1,222 @@
+//! Checking the settings in the environment before the server starts. Each
+//! module reads its own settings and falls back to a default when one can't
+//! be parsed, so without this a typo like IMAGE_ID_LENGTH=abc or a wrong
+//! GEOIP_DATABASE_PATH would quietly change how the server behaves.
+
+use std::path::Path;
+
+/// What the value of a setting has to look like
+enum Kind {
+    /// A whole number, 0 or more
+    Count,
+    /// A whole number, 1 or more
+    Positive,
+    Port,
+    Bool,
+    CachePolicy,
+    Probability,
+    /// A readable file
+    File,
+    Directory,
+    /// A comma separated list of IPs
+    Ips,
+    /// A comma separated list of IPs or CIDR ranges
+    IpRanges,
+}
+
+/// Every setting with a value that can be wrong, unset or empty ones use
+/// their default
+const SETTINGS: &[(&str, Kind)] = &[
+    ("IMAGE_ID_LENGTH", Kind::Positive),
+    ("HTTPS_REDIRECT_PORT", Kind::Port),
+    ("ROBOTS_TXT_PATH", Kind::File),
+    ("TRACK_UPLOAD_SOURCE", Kind::Bool),
+    ("ENABLE_DEBUG_ENDPOINT", Kind::Bool),
+    ("ENABLE_CANARY_ENDPOINT", Kind::Bool),
+    ("UPLOAD_SPOOL_DIR", Kind::Directory),
+    ("DEFAULT_CACHE_POLICY", Kind::CachePolicy),
+    ("NOT_FOUND_PLACEHOLDER_PATH", Kind::File),
+    ("GONE_PLACEHOLDER_PATH", Kind::File),
+    ("MAX_CONCURRENT_UPLOADS_PER_IP", Kind::Positive),
+    ("UPLOAD_TIMEOUT_SECONDS", Kind::Positive),
+    ("MIN_UPLOAD_BYTES_PER_SECOND", Kind::Count),
+    ("MAX_MEMORY_MEGABYTES", Kind::Positive),
+    ("MAX_UPLOADS_IN_FLIGHT", Kind::Positive),
+    ("PASSWORD_ATTEMPTS_PER_MINUTE", Kind::Positive),
+    ("PASSWORD_FAILURES_BEFORE_LOCKOUT", Kind::Positive),
+    ("MAX_CONCURRENT_PASSWORD_CHECKS", Kind::Positive),
+    ("MAX_CONCURRENT_TRANSCODES", Kind::Positive),
+    ("TRUSTED_PROXIES", Kind::IpRanges),
+    ("GEOIP_DATABASE_PATH", Kind::File),
+    ("UPLOAD_BLOCK_ANONYMOUS_PROXIES", Kind::Bool),
+    ("UPLOAD_REGION_BYPASS_IPS", Kind::Ips),
+    ("MONGODB_MIN_POOL_SIZE", Kind::Count),
+    ("MONGODB_MAX_POOL_SIZE", Kind::Positive),
+    ("MONGODB_CONNECT_TIMEOUT_SECONDS", Kind::Count),
+    ("MONGODB_SERVER_SELECTION_TIMEOUT_SECONDS", Kind::Count),
+    ("MONGODB_SLOW_QUERY_MS", Kind::Count),
+    ("CHAOS_MODE", Kind::Bool),
+    ("CHAOS_LATENCY_PROBABILITY", Kind::Probability),
+    ("CHAOS_MAX_LATENCY_MS", Kind::Count),
+    ("CHAOS_ERROR_PROBABILITY", Kind::Probability),
+    ("CHAOS_STORAGE_FAILURE_PROBABILITY", Kind::Probability),
+];
+
+/// Why `value` isn't a valid value of `kind`, if it isn't
+fn check_value(kind: &Kind, value: &str) -> Option<String> {
+    let value = value.trim();
+    let valid = match kind {
+        Kind::Count => value.parse::<u64>().is_ok(),
+        Kind::Positive => value.parse::<u64>().is_ok_and(|n| n > 0),
+        Kind::Port => value.parse::<u16>().is_ok(),
+        Kind::Bool => matches!(
+            value.to_ascii_lowercase().as_str(),
+            "true" | "1" | "on" | "yes" | "false" | "0" | "off" | "no"
+        ),
+        Kind::CachePolicy => crate::cache::CachePolicy::parse(value).is_some(),
+        Kind::Probability => crate::chaos::parse_probability(value).is_some(),
+        Kind::File => {
+            return std::fs::File::open(value)
+                .err()
+                .map(|e| format!("{} can't be read ({})", value, e))
+        }
+        Kind::Directory => Path::new(value).is_dir(),
+        Kind::Ips => list(value).all(|ip| ip.parse::<std::net::IpAddr>().is_ok()),
+        Kind::IpRanges => list(value).all(crate::proxy::is_valid_trusted_proxy),
+    };
+    if valid {
+        return None;
+    }
+    let expected = match kind {
+        Kind::Count => "a whole number",
+        Kind::Positive => "a whole number more than 0",
+        Kind::Port => "a port number",
+        Kind::Bool => "true or false",
+        Kind::CachePolicy => "revalidate, immutable or a number of seconds",
+        Kind::Probability => "a number from 0 to 1",
+        Kind::File => "a readable file",
+        Kind::Directory => "an existing directory",
+        Kind::Ips => "a comma separated list of IPs",
+        Kind::IpRanges => "a comma separated list of IPs or CIDR ranges",
+    };
+    Some(format!("{:?} isn't {}", value, expected))
+}
+
+/// Split a comma separated list, ignoring empty items
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Everything wrong with the settings `var` returns
+fn check_settings(var: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    let mut errors: Vec<String> = SETTINGS
+        .iter()
+        .filter_map(|(name, kind)| {
+            let value = var(name).filter(|value| !value.trim().is_empty())?;
+            let error = check_value(kind, &value)?;
+            Some(format!("{}: {}", name, error))
+        })
+        .collect();
+
+    let blocks_countries = var("UPLOAD_BLOCKED_COUNTRIES").is_some_and(|c| list(&c).count() > 0);
+    let blocks_proxies = var("UPLOAD_BLOCK_ANONYMOUS_PROXIES")
+        .is_some_and(|block| crate::util::parse_bool_field(&block));
+    if blocks_countries || blocks_proxies {
+        match var("GEOIP_DATABASE_PATH") {
+            None => errors.push(
+                "GEOIP_DATABASE_PATH: has to be set to block uploads by region, \
+                 otherwise nothing is blocked"
+                    .to_string(),
+            ),
+            Some(path) => {
+                if let Err(e) = maxminddb::Reader::open_readfile(&path) {
+                    errors.push(format!(
+                        "GEOIP_DATABASE_PATH: {} isn't a GeoIP database ({}), \
+                         so nothing would be blocked",
+                        path, e
+                    ));
+                }
+            }
+        }
+    }
+
+    let number = |name: &str| var(name).and_then(|n| n.trim().parse::<u64>().ok());
+    if let (Some(min), Some(max)) = (
+        number("MONGODB_MIN_POOL_SIZE"),
+        number("MONGODB_MAX_POOL_SIZE"),
+    ) {
+        if min > max {
+            errors.push(format!(
+                "MONGODB_MIN_POOL_SIZE: {} is more than MONGODB_MAX_POOL_SIZE ({})",
+                min, max
+            ));
+        }
+    }
+    errors
+}
+
+/// Check the settings in the environment, returning everything that's wrong
+/// with them
+pub fn check() -> Result<(), Vec<String>> {
+    let errors = check_settings(|name| std::env::var(name).ok());
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn check_vars(vars: &[(&str, &str)]) -> Vec<String> {
+        check_settings(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+    #[test]
+    fn empty_config_is_valid() {
+        assert!(check_vars(&[]).is_empty());
+    }
+    #[test]
+    fn bad_values_are_reported() {
+        let errors = check_vars(&[
+            ("IMAGE_ID_LENGTH", "abc"),
+            ("MAX_CONCURRENT_TRANSCODES", "0"),
+            ("TRACK_UPLOAD_SOURCE", "ture"),
+            ("TRUSTED_PROXIES", "10.0.0.0/8, proxy"),
+            ("UPLOAD_TIMEOUT_SECONDS", "60"),
+        ]);
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].starts_with("IMAGE_ID_LENGTH: \"abc\" isn't a whole number"));
+    }
+    #[test]
+    fn region_blocking_needs_geoip_database() {
+        let errors = check_vars(&[("UPLOAD_BLOCKED_COUNTRIES", "KP")]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("GEOIP_DATABASE_PATH: has to be set"));
+
+        let path = std::env::temp_dir().join("config_check_not_geoip");
+        std::fs::write(&path, b"not a database").unwrap();
+        let errors = check_vars(&[
+            ("UPLOAD_BLOCK_ANONYMOUS_PROXIES", "true"),
+            ("GEOIP_DATABASE_PATH", path.to_str().unwrap()),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("isn't a GeoIP database"));
+    }
+    #[test]
+    fn pool_sizes_are_checked_together() {
+        let errors = check_vars(&[
+            ("MONGODB_MIN_POOL_SIZE", "20"),
+            ("MONGODB_MAX_POOL_SIZE", "10"),
+        ]);
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -1,20 +1,91 @@
 //! Handles all the database operations.
 
+use crate::cache::CachePolicy;
+use crate::quality::QualityScore;
 use crate::util;
 
 use bson::spec::BinarySubtype;
+use futures::stream::TryStreamExt;
 use log::info;
 use mongodb::{
     bson::{doc, Document},
-    options::{ClientOptions, FindOneAndUpdateOptions, ResolverConfig, ReturnDocument},
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, ResolverConfig,
+        ReturnDocument, UpdateOptions,
+    },
     results::UpdateResult,
-    Client, Collection,
+    Client, Collection, IndexModel,
 };
 use std::env;
+use std::time::Duration;
 use util::ImageId;
 
+lazy_static! {
+    /// How many characters long new image ids are
+    static ref IMAGE_ID_LENGTH: usize = env::var("IMAGE_ID_LENGTH")
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .filter(|length| *length > 0)
+        .unwrap_or(5);
+}
+
+/// How many ids that already exist we generate before making them longer
+const ID_ATTEMPTS_BEFORE_GROWING: u32 = 10;
+
 pub struct Collections {
     pub images: Collection<Document>,
+    /// Daily counts of which external domains embed each image
+    pub embeds: Collection<Document>,
+    /// Tokens given out after entering the password of a protected image
+    pub image_sessions: Collection<Document>,
+    /// Links that give access to an image for a limited time or number of views
+    pub share_links: Collection<Document>,
+    /// Images converted to another format for /i/<id>.<ext>, so each is only
+    /// converted once. They expire after TRANSCODE_LIFETIME.
+    pub transcodes: Collection<Document>,
+}
+
+/// How long a converted image is kept before it has to be converted again
+const TRANSCODE_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How many domains embeds are counted for per image per day. The Referer
+/// header is set by the client, so without a cap anyone could add as many
+/// documents as they like.
+const MAX_EMBED_DOMAINS_PER_DAY: u64 = 100;
+
+/// What happened when trying to view an image through a share link
+pub enum ShareLinkUse {
+    /// The link is valid, this is the id of the image it's for
+    Valid(ImageId),
+    /// The link existed but has expired or run out of views
+    Gone,
+    NotFound,
+}
+
+/// Settings chosen by the uploader. Unlike the image data these are only
+/// written when the image is first inserted, so re-encoding an image in the
+/// background doesn't reset them.
+#[derive(Clone, Debug, Default)]
+pub struct ImageSettings {
+    /// Ask crawlers not to index the image or use it for AI training
+    pub noai: bool,
+    /// If set, the image is only served at /p/<id> after entering the password
+    pub password_hash: Option<String>,
+    /// Where the upload came from, only set if TRACK_UPLOAD_SOURCE is enabled
+    pub source: Option<UploadSource>,
+    /// How long the image can be cached, DEFAULT_CACHE_POLICY if not set
+    pub cache_policy: Option<CachePolicy>,
+    /// A license name like "CC-BY-4.0" or a URL, from `util::parse_license`
+    pub license: Option<String>,
+}
+
+/// Who uploaded an image, kept for tracking down abuse
+#[derive(Clone, Debug, Default)]
+pub struct UploadSource {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Sent by clients in the X-Client-Id header
+    pub client: Option<String>,
 }
 
 pub struct NewImage<'a> {
@@ -30,6 +101,11 @@ pub struct NewImage<'a> {
 
     pub thumbnail_data: &'a Vec<u8>,
     pub thumbnail_content_type: &'a str,
+
+    pub settings: &'a ImageSettings,
+    /// Assessed from the original upload, so like the settings it's only
+    /// written when the image is first inserted
+    pub quality: Option<QualityScore>,
 }
 
 /// Check if the image with the given id exists
@@ -48,7 +124,7 @@ pub async fn check_image_exists(
 }
 
 /// Connect to the MongoDB database
-pub async fn connect() -> Result<mongodb::Collection<bson::Document>, String> {
+pub async fn connect() -> Result<Collections, String> {
     // read the mongodb_uri env variable
     let mongodb_uri = match env::var("MONGODB_URI") {
         Ok(val) => val,
@@ -63,13 +139,14 @@ pub async fn connect() -> Result<mongodb::Collection<bson::Document>, String> {
     info!("Parsing mongodb uri: {}", mongodb_uri);
     // create the client options, we specify cloudflare because otherwise it takes forever to resolve a dns thing on windows
     // https://github.com/mongodb/mongo-rust-driver#windows-dns-note
-    let client_options =
+    let mut client_options =
         match ClientOptions::parse_with_resolver_config(mongodb_uri, ResolverConfig::cloudflare())
             .await
         {
             Ok(val) => val,
             Err(err) => return Err(err.to_string()),
         };
+    crate::db_pool::configure(&mut client_options);
 
     let client = match Client::with_options(client_options) {
         Ok(val) => val,
@@ -77,6 +154,10 @@ pub async fn connect() -> Result<mongodb::Collection<bson::Document>, String> {
     };
     let db = client.database(&mongodb_db_name);
     let images_collection = db.collection::<Document>("images");
+    let embeds_collection = db.collection::<Document>("embeds");
+    let image_sessions_collection = db.collection::<Document>("image_sessions");
+    let share_links_collection = db.collection::<Document>("share_links");
+    let transcodes_collection = db.collection::<Document>("transcodes");
 
     info!("Pinging database");
     match client
@@ -88,17 +169,93 @@ pub async fn connect() -> Result<mongodb::Collection<bson::Document>, String> {
         Err(err) => return Err(err.to_string()),
     };
 
-    Ok(images_collection)
+    embeds_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"image_id": 1, "domain": 1, "day": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    // for counting the domains an image is embedded on each day
+    embeds_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"image_id": 1, "day": 1})
+                .build(),
+            None,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    // for finding everything uploaded from an abusive ip
+    images_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"source.ip": 1})
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    // mongodb deletes the sessions by itself once they expire
+    image_sessions_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"expires": 1})
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(Duration::from_secs(0))
+                        .build(),
+                )
+                .build(),
+            None,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    // converted images are only a cache, so they're thrown away eventually
+    transcodes_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"created": 1})
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(TRANSCODE_LIFETIME)
+                        .build(),
+                )
+                .build(),
+            None,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(Collections {
+        images: images_collection,
+        embeds: embeds_collection,
+        image_sessions: image_sessions_collection,
+        share_links: share_links_collection,
+        transcodes: transcodes_collection,
+    })
 }
 
-/// Generate a random non-duplicate image id
+/// Generate a random non-duplicate image id. Ids are IMAGE_ID_LENGTH long,
+/// but if we keep hitting existing ones the id gets longer so we don't loop
+/// forever once most short ids are taken.
 pub async fn generate_image_id(
     images_collection: &Collection<Document>,
 ) -> Result<ImageId, mongodb::error::Error> {
     info!("generating image id");
-    let mut id = util::generate_random_id(5);
+    let mut length = *IMAGE_ID_LENGTH;
+    let mut attempts = 0;
+    let mut id = util::generate_random_id(length);
     while check_image_exists(images_collection, id.clone()).await? {
-        id = util::generate_random_id(5);
+        attempts += 1;
+        if attempts % ID_ATTEMPTS_BEFORE_GROWING == 0 {
+            length += 1;
+        }
+        id = util::generate_random_id(length);
     }
     info!("generated image id");
     Ok(id)
@@ -110,6 +267,7 @@ pub async fn insert_image(
     image: &NewImage<'_>,
 ) -> Result<Option<bson::Document>, mongodb::error::Error> {
     info!("inserting doc");
+    crate::chaos::storage_fault()?;
     images_collection
         .find_one_and_update(
             doc! {
@@ -119,13 +277,27 @@ pub async fn insert_image(
                 "$setOnInsert": {
                     "date": bson::DateTime::now(),                    
                     "last_seen": bson::DateTime::now(),
+                    "noai": image.settings.noai,
+                    "password_hash": image.settings.password_hash.clone(),
+                    "cache_policy": image.settings.cache_policy.map(|policy| policy.to_string()),
+                    "license": image.settings.license.clone(),
+                    "source": image.settings.source.as_ref().map(|source| doc! {
+                        "ip": source.ip.clone(),
+                        "user_agent": source.user_agent.clone(),
+                        "client": source.client.clone(),
+                    }),
+                    "quality": image.quality.map(|quality| doc! {
+                        "score": quality.score,
+                        "sharpness": quality.sharpness,
+                        "exposure": quality.exposure,
+                    }),
                 },
                 "$set": {
                     "data": bson::Binary { subtype: BinarySubtype::Generic, bytes: image.data.to_vec() },
                     "content_type": image.content_type,
 
                     "width": image.size.0,
-                    "height": image.size.0,
+                    "height": image.size.1,
 
                     "thumbnail_data": bson::Binary { subtype: BinarySubtype::Generic, bytes: image.thumbnail_data.to_vec() },
                     "thumbnail_content_type": image.thumbnail_content_type,
@@ -158,10 +330,307 @@ pub async fn update_last_seen(
         .await
 }
 
+/// Replace the thumbnail of an image, for when it had to be regenerated
+pub async fn set_thumbnail(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+    data: &[u8],
+    content_type: &str,
+) -> Result<UpdateResult, mongodb::error::Error> {
+    images_collection
+        .update_one(
+            doc! {
+                "_id": image_id.to_string(),
+            },
+            doc! {
+                "$set": {
+                    "thumbnail_data": bson::Binary { subtype: BinarySubtype::Generic, bytes: data.to_vec() },
+                    "thumbnail_content_type": content_type,
+                }
+            },
+            None,
+        )
+        .await
+}
+
+/// Get an image converted to the format of `extension`, if it was converted
+/// from the image at `optim_level`. Re-encoding the image in the background
+/// bumps its optim_level, so older conversions aren't used.
+pub async fn get_transcode(
+    transcodes_collection: &Collection<Document>,
+    image_id: &str,
+    extension: &str,
+    optim_level: i64,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    transcodes_collection
+        .find_one(
+            doc! {
+                "_id": format!("{}.{}", image_id, extension),
+                "optim_level": optim_level,
+            },
+            None,
+        )
+        .await
+}
+
+/// Store an image converted to the format of `extension`, replacing older
+/// conversions to the same format
+pub async fn set_transcode(
+    transcodes_collection: &Collection<Document>,
+    image_id: &str,
+    extension: &str,
+    optim_level: i64,
+    data: &[u8],
+    content_type: &str,
+) -> Result<UpdateResult, mongodb::error::Error> {
+    transcodes_collection
+        .update_one(
+            doc! {"_id": format!("{}.{}", image_id, extension)},
+            doc! {
+                "$set": {
+                    "image_id": image_id,
+                    "optim_level": optim_level,
+                    "data": bson::Binary { subtype: BinarySubtype::Generic, bytes: data.to_vec() },
+                    "content_type": content_type,
+                    "created": bson::DateTime::now(),
+                }
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await
+}
+
+/// Delete an image, returns whether it existed
+pub async fn delete_image(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+) -> Result<bool, mongodb::error::Error> {
+    let result = images_collection
+        .delete_one(doc! {"_id": image_id.to_string()}, None)
+        .await?;
+    Ok(result.deleted_count > 0)
+}
+
 pub async fn get_image(
     images_collection: &Collection<Document>,
     id: &str,
 ) -> Result<Option<Document>, mongodb::error::Error> {
+    crate::chaos::storage_fault()?;
     let filter = doc! {"_id": id};
     images_collection.find_one(filter, None).await
 }
+
+/// Count a view of an image that was embedded on another domain. Once
+/// MAX_EMBED_DOMAINS_PER_DAY domains have been counted for the image today,
+/// only those keep being counted.
+pub async fn record_embed(
+    embeds_collection: &Collection<Document>,
+    image_id: &ImageId,
+    domain: &str,
+) -> Result<(), mongodb::error::Error> {
+    let now = bson::DateTime::now().timestamp_millis();
+    let day = bson::DateTime::from_millis(now - now.rem_euclid(86_400_000));
+    let filter = doc! {
+        "image_id": image_id.to_string(),
+        "domain": domain,
+        "day": day,
+    };
+    let update = doc! {
+        "$inc": {"count": 1_i64},
+    };
+    let existing = embeds_collection
+        .update_one(filter.clone(), update.clone(), None)
+        .await?;
+    if existing.matched_count > 0 {
+        return Ok(());
+    }
+    let domains_today = embeds_collection
+        .count_documents(doc! {"image_id": image_id.to_string(), "day": day}, None)
+        .await?;
+    if domains_today >= MAX_EMBED_DOMAINS_PER_DAY {
+        info!(
+            "Not counting embed of {} on {}, already counting {} domains today",
+            image_id, domain, domains_today
+        );
+        return Ok(());
+    }
+    embeds_collection
+        .update_one(
+            filter,
+            update,
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Get the daily embed counts for an image, newest first
+pub async fn get_embeds(
+    embeds_collection: &Collection<Document>,
+    image_id: &ImageId,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    embeds_collection
+        .find(
+            doc! {"image_id": image_id.to_string()},
+            FindOptions::builder()
+                .sort(doc! {"day": -1, "count": -1})
+                .limit(1000)
+                .build(),
+        )
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Get the metadata fields of the images with the given ids in one query,
+/// without the image data, and with the size of the image data in "size"
+/// (null if the data isn't binary). Ids that don't exist are left out.
+pub async fn get_images_metadata(
+    images_collection: &Collection<Document>,
+    ids: &[String],
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    crate::chaos::storage_fault()?;
+    images_collection
+        .find(
+            doc! {"_id": {"$in": ids}},
+            FindOptions::builder()
+                .projection(doc! {
+                    "content_type": 1,
+                    "thumbnail_content_type": 1,
+                    "width": 1,
+                    "height": 1,
+                    "date": 1,
+                    "noai": 1,
+                    "license": 1,
+                    "password_hash": 1,
+                    "quality": 1,
+                    "cache_policy": 1,
+                    "optim_level": 1,
+                    "size": {"$cond": [
+                        {"$eq": [{"$type": "$data"}, "binData"]},
+                        {"$binarySize": "$data"},
+                        null,
+                    ]},
+                })
+                .build(),
+        )
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Create a token that lets the holder view a password protected image
+pub async fn create_image_session(
+    image_sessions_collection: &Collection<Document>,
+    image_id: &ImageId,
+    lifetime: Duration,
+) -> Result<String, mongodb::error::Error> {
+    let token = util::generate_random_string(
+        32,
+        b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
+    );
+    let expires = bson::DateTime::from_millis(
+        bson::DateTime::now().timestamp_millis() + lifetime.as_millis() as i64,
+    );
+    image_sessions_collection
+        .insert_one(
+            doc! {
+                "_id": &token,
+                "image_id": image_id.to_string(),
+                "expires": expires,
+            },
+            None,
+        )
+        .await?;
+    Ok(token)
+}
+
+/// Check if the token was given out for the image and hasn't expired yet
+pub async fn check_image_session(
+    image_sessions_collection: &Collection<Document>,
+    image_id: &ImageId,
+    token: &str,
+) -> Result<bool, mongodb::error::Error> {
+    let session = image_sessions_collection
+        .find_one(
+            doc! {
+                "_id": token,
+                "image_id": image_id.to_string(),
+                "expires": {"$gt": bson::DateTime::now()},
+            },
+            None,
+        )
+        .await?;
+    Ok(session.is_some())
+}
+
+/// Create a link to an image that stops working after `max_views` views or
+/// at `expires`, whichever comes first
+pub async fn create_share_link(
+    share_links_collection: &Collection<Document>,
+    image_id: &ImageId,
+    max_views: Option<i64>,
+    expires: Option<bson::DateTime>,
+) -> Result<String, mongodb::error::Error> {
+    let token = util::generate_random_string(
+        16,
+        b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
+    );
+    share_links_collection
+        .insert_one(
+            doc! {
+                "_id": &token,
+                "image_id": image_id.to_string(),
+                "date": bson::DateTime::now(),
+                "remaining_views": max_views,
+                "expires": expires,
+            },
+            None,
+        )
+        .await?;
+    Ok(token)
+}
+
+/// Use up a view of a share link if it's still valid
+pub async fn use_share_link(
+    share_links_collection: &Collection<Document>,
+    token: &str,
+) -> Result<ShareLinkUse, mongodb::error::Error> {
+    let link = match share_links_collection
+        .find_one(doc! {"_id": token}, None)
+        .await?
+    {
+        Some(link) => link,
+        None => return Ok(ShareLinkUse::NotFound),
+    };
+    let image_id = match link.get_str("image_id") {
+        Ok(image_id) => ImageId(image_id.to_string()),
+        Err(_) => return Ok(ShareLinkUse::NotFound),
+    };
+    let now = bson::DateTime::now();
+    if matches!(link.get_datetime("expires"), Ok(expires) if *expires <= now) {
+        return Ok(ShareLinkUse::Gone);
+    }
+    if link.get_i64("remaining_views").is_err() {
+        // unlimited views
+        return Ok(ShareLinkUse::Valid(image_id));
+    }
+
+    // decrement in the filter so concurrent views can't go past the limit
+    let used = share_links_collection
+        .find_one_and_update(
+            doc! {
+                "_id": token,
+                "remaining_views": {"$gt": 0_i64},
+            },
+            doc! {
+                "$inc": {"remaining_views": -1_i64},
+            },
+            None,
+        )
+        .await?;
+    Ok(match used {
+        Some(_) => ShareLinkUse::Valid(image_id),
+        None => ShareLinkUse::Gone,
+    })
+}
@@ -0,0 +1,177 @@
+//! MongoDB connection pool tuning, pool utilization gauges and slow query
+//! logging.
+
+use mongodb::event::cmap::{
+    CmapEventHandler, ConnectionCheckedInEvent, ConnectionCheckedOutEvent,
+    ConnectionCheckoutFailedEvent, ConnectionClosedEvent, ConnectionCreatedEvent,
+};
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use mongodb::options::ClientOptions;
+use rocket::serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+lazy_static! {
+    /// Connections kept open even when idle, the driver default (0) if unset
+    static ref MIN_POOL_SIZE: Option<u32> = env_number("MONGODB_MIN_POOL_SIZE");
+    /// Most connections open at once, the driver default (10) if unset
+    static ref MAX_POOL_SIZE: Option<u32> =
+        env_number("MONGODB_MAX_POOL_SIZE").filter(|size| *size > 0);
+    /// How long opening a connection may take
+    static ref CONNECT_TIMEOUT: Option<Duration> =
+        env_number("MONGODB_CONNECT_TIMEOUT_SECONDS").map(Duration::from_secs);
+    /// How long an operation waits for a usable server before failing
+    static ref SERVER_SELECTION_TIMEOUT: Option<Duration> =
+        env_number("MONGODB_SERVER_SELECTION_TIMEOUT_SECONDS").map(Duration::from_secs);
+    /// Commands that take longer than this are logged, not logged at all if unset
+    static ref SLOW_QUERY_THRESHOLD: Option<Duration> =
+        env_number("MONGODB_SLOW_QUERY_MS").map(Duration::from_millis);
+}
+
+/// Counts of what the connection pool is doing, updated from driver events
+pub struct PoolStats {
+    open: AtomicU64,
+    in_use: AtomicU64,
+    checkout_failures: AtomicU64,
+}
+
+pub static POOL_STATS: PoolStats = PoolStats::new();
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct PoolSnapshot {
+    pub connections_open: u64,
+    pub connections_in_use: u64,
+    /// None if it's the driver default
+    pub max_pool_size: Option<u32>,
+    pub checkout_failures: u64,
+}
+
+impl PoolStats {
+    const fn new() -> Self {
+        PoolStats {
+            open: AtomicU64::new(0),
+            in_use: AtomicU64::new(0),
+            checkout_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            connections_open: self.open.load(Ordering::Relaxed),
+            connections_in_use: self.in_use.load(Ordering::Relaxed),
+            max_pool_size: *MAX_POOL_SIZE,
+            checkout_failures: self.checkout_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decrement without wrapping, in case we missed the matching increment
+fn saturating_decrement(counter: &AtomicU64) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+}
+
+struct PoolMonitor(&'static PoolStats);
+
+impl CmapEventHandler for PoolMonitor {
+    fn handle_connection_created_event(&self, _event: ConnectionCreatedEvent) {
+        self.0.open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle_connection_closed_event(&self, _event: ConnectionClosedEvent) {
+        saturating_decrement(&self.0.open);
+    }
+
+    fn handle_connection_checked_out_event(&self, _event: ConnectionCheckedOutEvent) {
+        self.0.in_use.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle_connection_checked_in_event(&self, _event: ConnectionCheckedInEvent) {
+        saturating_decrement(&self.0.in_use);
+    }
+
+    fn handle_connection_checkout_failed_event(&self, event: ConnectionCheckoutFailedEvent) {
+        let total = self.0.checkout_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "Couldn't check out a database connection: {:?} ({} so far)",
+            event.reason, total
+        );
+    }
+}
+
+struct SlowQueryLogger(Duration);
+
+impl CommandEventHandler for SlowQueryLogger {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        if event.duration >= self.0 {
+            warn!(
+                "Slow database command {} took {}ms (request {})",
+                event.command_name,
+                event.duration.as_millis(),
+                event.request_id
+            );
+        }
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        if event.duration >= self.0 {
+            warn!(
+                "Slow database command {} failed after {}ms (request {}): {}",
+                event.command_name,
+                event.duration.as_millis(),
+                event.request_id,
+                event.failure
+            );
+        }
+    }
+}
+
+/// Apply the pool settings from the environment on top of the ones from the
+/// connection string, and hook up the pool gauges and slow query logging
+pub fn configure(options: &mut ClientOptions) {
+    if let Some(size) = *MIN_POOL_SIZE {
+        options.min_pool_size = Some(size);
+    }
+    if let Some(size) = *MAX_POOL_SIZE {
+        options.max_pool_size = Some(size);
+    }
+    if let Some(timeout) = *CONNECT_TIMEOUT {
+        options.connect_timeout = Some(timeout);
+    }
+    if let Some(timeout) = *SERVER_SELECTION_TIMEOUT {
+        options.server_selection_timeout = Some(timeout);
+    }
+    options.cmap_event_handler = Some(Arc::new(PoolMonitor(&POOL_STATS)));
+    if let Some(threshold) = *SLOW_QUERY_THRESHOLD {
+        options.command_event_handler = Some(Arc::new(SlowQueryLogger(threshold)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn saturating_decrement_works() {
+        let counter = AtomicU64::new(1);
+        saturating_decrement(&counter);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+        saturating_decrement(&counter);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+    }
+    #[test]
+    fn pool_stats_snapshot_works() {
+        let stats = PoolStats::new();
+        stats.open.fetch_add(3, Ordering::Relaxed);
+        stats.in_use.fetch_add(2, Ordering::Relaxed);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.connections_open, 3);
+        assert_eq!(snapshot.connections_in_use, 2);
+        assert_eq!(snapshot.checkout_failures, 0);
+    }
+}
@@ -11,6 +11,9 @@ use std::{fmt::Debug, path::PathBuf};
 use tokio::task;
 use tokio::task::JoinHandle;
 
+/// The max width and height of thumbnails
+pub const THUMBNAIL_SIZE: u32 = 128;
+
 pub struct EncodeResult {
     pub data: Vec<u8>,
     pub size: (u32, u32),
@@ -18,6 +21,7 @@ pub struct EncodeResult {
 }
 
 /// Encode an image as a Webp from the given file path
+#[allow(dead_code)]
 pub async fn image_path_to_encoded(
     path: Box<PathBuf>,
     content_type: &'_ str,
@@ -49,6 +53,66 @@ pub async fn image_path_to_encoded(
     from_image(decoded_image, opts).await
 }
 
+/// Decode an image from bytes that are in the given format
+async fn decode(bytes: Vec<u8>, content_type: &'_ str) -> Result<DynamicImage, String> {
+    let mut read_image = ImageReader::new(Cursor::new(bytes));
+    read_image.set_format(util::mimetype_to_format(content_type));
+
+    task::spawn_blocking(move || read_image.decode())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|_| "Error decoding image".to_string())
+}
+
+/// Encode an image from bytes that are in the given format
+pub async fn bytes_to_encoded(
+    bytes: Vec<u8>,
+    content_type: &'_ str,
+    opts: FromImageOptions,
+) -> Result<EncodeResult, String> {
+    from_image(decode(bytes, content_type).await?, opts).await
+}
+
+/// The mime type for a file extension like "png", if it's a format we can
+/// transcode to
+pub fn extension_to_mimetype(extension: &str) -> Option<&'static str> {
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Convert an image to another format without resizing it, `target` should
+/// come from `extension_to_mimetype`
+pub async fn transcode(
+    bytes: Vec<u8>,
+    content_type: &'_ str,
+    target: &'static str,
+) -> Result<Vec<u8>, String> {
+    let im = decode(bytes, content_type).await?;
+    task::spawn_blocking(move || {
+        if target == "image/webp" {
+            // the webp encoder only takes 8 bit rgb(a)
+            return to_webp(&DynamicImage::ImageRgba8(im.to_rgba8())).map(|r| r.data);
+        }
+        // jpeg can't have transparency
+        let im = if target == "image/jpeg" {
+            DynamicImage::ImageRgb8(im.to_rgb8())
+        } else {
+            im
+        };
+        let mut bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        im.write_to(&mut bytes, util::mimetype_to_format(target))
+            .map_err(|e| format!("Error writing {}: {}", target, e))?;
+        Ok(bytes.into_inner())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
 struct CompressedImageResult {
     data: Vec<u8>,
     content_type: String,
@@ -59,7 +123,7 @@ fn to_webp(im: &DynamicImage) -> Result<CompressedImageResult, String> {
     info!("encoding webp");
     let encoder = match webp::Encoder::from_image(im) {
         Ok(i) => i,
-        Err(e) => return Err(format!("Error making encoder for webp: {}", e.to_string())),
+        Err(e) => return Err(format!("Error making encoder for webp: {}", e)),
     };
     let image_bytes = (*encoder.encode(90.0)).to_vec();
     info!("encoded webp");
@@ -75,12 +139,12 @@ fn to_png(im: &DynamicImage) -> Result<CompressedImageResult, String> {
     let mut bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
     match im.write_to(&mut bytes, image::ImageOutputFormat::Png) {
         Ok(_) => (),
-        Err(e) => return Err(format!("Error writing png: {}", e.to_string())),
+        Err(e) => return Err(format!("Error writing png: {}", e)),
     };
     let image_bytes =
         match oxipng::optimize_from_memory(&bytes.into_inner()[..], &oxipng::Options::default()) {
             Ok(r) => r,
-            Err(e) => return Err(format!("Error optimizing png: {}", e.to_string())),
+            Err(e) => return Err(format!("Error optimizing png: {}", e)),
         };
 
     Ok(CompressedImageResult {
@@ -90,7 +154,7 @@ fn to_png(im: &DynamicImage) -> Result<CompressedImageResult, String> {
 }
 
 #[non_exhaustive]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct FromImageOptions {
     /// The max width and height of the image
     pub max_size: Option<u32>,
@@ -98,19 +162,10 @@ pub struct FromImageOptions {
     pub optimize_png: bool,
 }
 
-impl Default for FromImageOptions {
-    fn default() -> FromImageOptions {
-        FromImageOptions {
-            max_size: None,
-            optimize_png: false,
-        }
-    }
-}
-
 /// Take in the current size of the image along with a new desired max height
 /// and return the new size. If both the width and height are smaller than
 /// the max height, their old values are returned
-fn clamp_im_size(width: u32, height: u32, max_size: u32) -> (u32, u32) {
+pub fn clamp_im_size(width: u32, height: u32, max_size: u32) -> (u32, u32) {
     // they're both within the size, we don't need to do anything
     if width < max_size && height < max_size {
         return (width, height);
@@ -125,33 +180,8 @@ fn clamp_im_size(width: u32, height: u32, max_size: u32) -> (u32, u32) {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn clamp_im_size_already_smaller() {
-        let (w, h) = clamp_im_size(32, 64, 64);
-        assert_eq!((w, h), (32, 64));
-    }
-    #[test]
-    fn clamp_im_height_bigger() {
-        let (w, h) = clamp_im_size(64, 256, 16);
-        assert_eq!((w, h), (4, 16));
-    }
-    #[test]
-    fn clamp_im_width_bigger() {
-        let (w, h) = clamp_im_size(256, 64, 16);
-        assert_eq!((w, h), (16, 4));
-    }
-    #[test]
-    fn clamp_im_uneven() {
-        let (w, h) = clamp_im_size(112, 398, 256);
-        assert_eq!((w, h), (72, 256));
-    }
-}
-
 /// Convert a dynamic image into an optimized image
-pub async fn from_image<'a>(
+pub async fn from_image(
     original_im: DynamicImage,
     opts: FromImageOptions,
 ) -> Result<EncodeResult, String> {
@@ -220,3 +250,111 @@ pub async fn from_image<'a>(
         content_type: compressed_image_result.content_type.to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{checkerboard, gradient, pixel_hash};
+
+    /// Pixel hash of `gradient(300, 200)` after `from_image` makes it into a
+    /// thumbnail, decoded again. This covers both the resize and the webp
+    /// encoding, so update it if either changes on purpose (including a
+    /// libwebp upgrade).
+    const GRADIENT_THUMBNAIL_HASH: u64 = 9290167802773533796;
+
+    #[test]
+    fn clamp_im_size_already_smaller() {
+        let (w, h) = clamp_im_size(32, 64, 64);
+        assert_eq!((w, h), (32, 64));
+    }
+    #[test]
+    fn clamp_im_height_bigger() {
+        let (w, h) = clamp_im_size(64, 256, 16);
+        assert_eq!((w, h), (4, 16));
+    }
+    #[test]
+    fn clamp_im_width_bigger() {
+        let (w, h) = clamp_im_size(256, 64, 16);
+        assert_eq!((w, h), (16, 4));
+    }
+    #[test]
+    fn clamp_im_uneven() {
+        let (w, h) = clamp_im_size(112, 398, 256);
+        assert_eq!((w, h), (72, 256));
+    }
+    #[rocket::async_test]
+    async fn thumbnail_matches_golden() {
+        // the same options as the thumbnails made on upload
+        let encoded = from_image(
+            gradient(300, 200),
+            FromImageOptions {
+                max_size: Some(THUMBNAIL_SIZE),
+                ..FromImageOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(encoded.size, (128, 85));
+        assert_eq!(encoded.content_type, "image/webp");
+        let decoded = image::load_from_memory(&encoded.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (128, 85));
+        assert_eq!(pixel_hash(&decoded), GRADIENT_THUMBNAIL_HASH);
+    }
+    #[rocket::async_test]
+    async fn from_image_keeps_small_images() {
+        let encoded = from_image(gradient(64, 32), FromImageOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(encoded.size, (64, 32));
+    }
+    #[rocket::async_test]
+    async fn from_image_picks_smallest_format() {
+        let webp_only = from_image(checkerboard(256, 256, 32), FromImageOptions::default())
+            .await
+            .unwrap();
+        let with_png = from_image(
+            checkerboard(256, 256, 32),
+            FromImageOptions {
+                optimize_png: true,
+                ..FromImageOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(with_png.data.len() <= webp_only.data.len());
+    }
+    #[test]
+    fn extension_to_mimetype_works() {
+        assert_eq!(extension_to_mimetype("JPG"), Some("image/jpeg"));
+        assert_eq!(extension_to_mimetype("webp"), Some("image/webp"));
+        assert_eq!(extension_to_mimetype("exe"), None);
+    }
+    #[rocket::async_test]
+    async fn transcode_changes_format() {
+        let png = to_png(&gradient(40, 30)).unwrap().data;
+        for (target, format) in [
+            ("image/jpeg", image::ImageFormat::Jpeg),
+            ("image/gif", image::ImageFormat::Gif),
+            ("image/webp", image::ImageFormat::WebP),
+        ] {
+            let transcoded = transcode(png.clone(), "image/png", target).await.unwrap();
+            assert_eq!(image::guess_format(&transcoded).unwrap(), format);
+            let decoded = image::load_from_memory(&transcoded).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (40, 30));
+        }
+    }
+    #[rocket::async_test]
+    async fn transcode_rejects_garbage() {
+        assert!(transcode(vec![1, 2, 3], "image/png", "image/jpeg")
+            .await
+            .is_err());
+    }
+    #[test]
+    fn to_png_is_lossless() {
+        let im = gradient(100, 50);
+        let png = to_png(&im).unwrap();
+        assert_eq!(png.content_type, "image/png");
+        let decoded = image::load_from_memory(&png.data).unwrap();
+        assert_eq!(pixel_hash(&decoded), pixel_hash(&im));
+    }
+}
@@ -0,0 +1,128 @@
+//! Restricting uploads by the country they come from, using a MaxMind
+//! GeoIP2/GeoLite2 Country database.
+
+use maxminddb::{geoip2, Reader};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use std::net::IpAddr;
+
+lazy_static! {
+    /// Nothing is restricted if GEOIP_DATABASE_PATH isn't set
+    static ref GEOIP_DATABASE: Option<Reader<Vec<u8>>> = std::env::var("GEOIP_DATABASE_PATH")
+        .ok()
+        .and_then(|path| match Reader::open_readfile(&path) {
+            Ok(reader) => Some(reader),
+            Err(e) => {
+                error!("Failed to open GeoIP database {}: {}", path, e);
+                None
+            }
+        });
+    /// ISO country codes that can't upload, like "KP,IR"
+    static ref BLOCKED_COUNTRIES: Vec<String> = parse_list(
+        &std::env::var("UPLOAD_BLOCKED_COUNTRIES").unwrap_or_default()
+    )
+    .into_iter()
+    .map(|country| country.to_ascii_uppercase())
+    .collect();
+    static ref BLOCK_ANONYMOUS_PROXIES: bool = std::env::var("UPLOAD_BLOCK_ANONYMOUS_PROXIES")
+        .map(|block| crate::util::parse_bool_field(&block))
+        .unwrap_or(false);
+    /// IPs that are never restricted
+    static ref BYPASS_IPS: Vec<IpAddr> = parse_list(
+        &std::env::var("UPLOAD_REGION_BYPASS_IPS").unwrap_or_default()
+    )
+    .iter()
+    .filter_map(|ip| ip.parse().ok())
+    .collect();
+}
+
+/// Split a comma separated list, ignoring empty items
+fn parse_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Whether a client from `country` should be blocked
+fn is_blocked(
+    country: Option<&str>,
+    is_anonymous_proxy: bool,
+    blocked_countries: &[String],
+    block_anonymous_proxies: bool,
+) -> bool {
+    if block_anonymous_proxies && is_anonymous_proxy {
+        return true;
+    }
+    match country {
+        Some(country) => blocked_countries
+            .iter()
+            .any(|blocked| blocked.eq_ignore_ascii_case(country)),
+        None => false,
+    }
+}
+
+/// A request guard that fails with 451 Unavailable For Legal Reasons if the
+/// client's IP is in one of UPLOAD_BLOCKED_COUNTRIES, or is an anonymous
+/// proxy when UPLOAD_BLOCK_ANONYMOUS_PROXIES is enabled.
+pub struct UploadRegionCheck;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadRegionCheck {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let (database, ip) = match (GEOIP_DATABASE.as_ref(), crate::proxy::client_ip(req)) {
+            (Some(database), Some(ip)) => (database, ip),
+            _ => return request::Outcome::Success(UploadRegionCheck),
+        };
+        if BYPASS_IPS.contains(&ip) {
+            return request::Outcome::Success(UploadRegionCheck);
+        }
+        // ips that aren't in the database (like private ones) are allowed
+        let record = match database.lookup::<geoip2::Country>(ip) {
+            Ok(record) => record,
+            Err(_) => return request::Outcome::Success(UploadRegionCheck),
+        };
+        let country = record.country.as_ref().and_then(|country| country.iso_code);
+        let is_anonymous_proxy = record
+            .traits
+            .as_ref()
+            .and_then(|traits| traits.is_anonymous_proxy)
+            .unwrap_or(false);
+
+        if is_blocked(
+            country,
+            is_anonymous_proxy,
+            &BLOCKED_COUNTRIES,
+            *BLOCK_ANONYMOUS_PROXIES,
+        ) {
+            info!("Blocked upload from {} ({:?})", ip, country);
+            return request::Outcome::Error((Status::UnavailableForLegalReasons, ()));
+        }
+        request::Outcome::Success(UploadRegionCheck)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_list_ignores_empty_items() {
+        assert_eq!(parse_list(" KP, IR,,"), vec!["KP", "IR"]);
+        assert!(parse_list("").is_empty());
+    }
+    #[test]
+    fn is_blocked_by_country() {
+        let blocked = vec!["KP".to_string()];
+        assert!(is_blocked(Some("KP"), false, &blocked, false));
+        assert!(is_blocked(Some("kp"), false, &blocked, false));
+        assert!(!is_blocked(Some("US"), false, &blocked, false));
+        assert!(!is_blocked(None, false, &blocked, false));
+    }
+    #[test]
+    fn is_blocked_anonymous_proxies() {
+        assert!(is_blocked(Some("US"), true, &[], true));
+        assert!(!is_blocked(Some("US"), true, &[], false));
+    }
+}
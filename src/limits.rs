@@ -0,0 +1,700 @@
+//! Limits on how much work a single client can make us do at once.
+
+use futures::Stream;
+use multer::bytes::Bytes;
+use rocket::data::{ByteUnit, Data, DataStream};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{OnceCell, Semaphore, SemaphorePermit};
+use tokio::time::{timeout_at, Instant};
+
+lazy_static! {
+    static ref UPLOADS_IN_FLIGHT: InFlight = InFlight::new(
+        std::env::var("MAX_CONCURRENT_UPLOADS_PER_IP")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(4)
+    );
+    /// The longest an upload body is allowed to take to arrive
+    static ref UPLOAD_TIMEOUT: Duration = Duration::from_secs(
+        std::env::var("UPLOAD_TIMEOUT_SECONDS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(60)
+    );
+    /// Upload bodies that arrive slower than this many bytes per second are dropped
+    static ref MIN_UPLOAD_BYTES_PER_SECOND: u64 = std::env::var("MIN_UPLOAD_BYTES_PER_SECOND")
+        .ok()
+        .and_then(|rate| rate.parse().ok())
+        .unwrap_or(1024);
+    /// Uploads are shed while the process uses more memory than this,
+    /// nothing is shed if MAX_MEMORY_MEGABYTES isn't set
+    static ref MAX_MEMORY_BYTES: Option<u64> = std::env::var("MAX_MEMORY_MEGABYTES")
+        .ok()
+        .and_then(|max| max.parse::<u64>().ok())
+        .map(|max| max * 1024 * 1024);
+    /// Uploads are shed while this many are already being handled across all
+    /// clients, nothing is shed if MAX_UPLOADS_IN_FLIGHT isn't set
+    static ref MAX_UPLOADS_IN_FLIGHT: Option<usize> = std::env::var("MAX_UPLOADS_IN_FLIGHT")
+        .ok()
+        .and_then(|max| max.parse().ok());
+    /// How many passwords each IP can try per PASSWORD_ATTEMPT_WINDOW
+    static ref PASSWORD_ATTEMPTS: AttemptWindow = AttemptWindow::new(
+        std::env::var("PASSWORD_ATTEMPTS_PER_MINUTE")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(10)
+    );
+    /// Wrong passwords in a row for a protected image, from any IP, before it
+    /// gets locked
+    static ref PASSWORD_FAILURES: FailureBackoff = FailureBackoff::new(
+        std::env::var("PASSWORD_FAILURES_BEFORE_LOCKOUT")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(5)
+    );
+    /// Checking a password takes a lot of CPU on purpose, so only this many
+    /// run at once across all clients
+    static ref PASSWORD_CHECKS: Semaphore = Semaphore::new(
+        std::env::var("MAX_CONCURRENT_PASSWORD_CHECKS")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(4)
+    );
+    /// Conversions that are running, by image, format and optim_level
+    static ref RUNNING_TRANSCODES: Coalescer<Result<Vec<u8>, Status>> = Coalescer::new();
+    /// Converting an image to another format for /i/<id>.<ext> takes a lot
+    /// of CPU, so only this many run at once across all clients
+    static ref TRANSCODES: Semaphore = Semaphore::new(
+        std::env::var("MAX_CONCURRENT_TRANSCODES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(2)
+    );
+}
+
+/// How long the PASSWORD_ATTEMPTS_PER_MINUTE window is
+const PASSWORD_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+/// Once this many ips are being tracked, ips whose window is over are forgotten
+const PASSWORD_ATTEMPT_PRUNE_SIZE: usize = 1024;
+
+/// The longest a protected image is locked for after wrong passwords
+const MAX_PASSWORD_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Wrong passwords are forgotten this long after the last one
+const PASSWORD_FAILURE_MEMORY: Duration = Duration::from_secs(60 * 60);
+
+/// How long a client can take before the minimum upload rate is enforced, so
+/// slow starts aren't punished
+const MIN_RATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+static TIMED_OUT_UPLOADS: AtomicU64 = AtomicU64::new(0);
+static SHED_UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts of uploads we gave up on since startup
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct UploadSnapshot {
+    /// Dropped for taking longer than UPLOAD_TIMEOUT_SECONDS or arriving
+    /// slower than MIN_UPLOAD_BYTES_PER_SECOND
+    pub timed_out_uploads: u64,
+    /// Turned away with 503 Service Unavailable for going over
+    /// MAX_MEMORY_MEGABYTES or MAX_UPLOADS_IN_FLIGHT
+    pub shed_uploads: u64,
+}
+
+pub fn upload_snapshot() -> UploadSnapshot {
+    UploadSnapshot {
+        timed_out_uploads: TIMED_OUT_UPLOADS.load(Ordering::Relaxed),
+        shed_uploads: SHED_UPLOADS.load(Ordering::Relaxed),
+    }
+}
+
+/// How often paused background work checks whether memory has been freed
+const PRESSURE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many seconds clients are told to wait after hitting a limit
+pub const RETRY_AFTER_SECONDS: u32 = 5;
+
+/// Counts the requests that are currently being handled for each IP
+struct InFlight {
+    max_per_ip: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl InFlight {
+    fn new(max_per_ip: usize) -> Self {
+        InFlight {
+            max_per_ip,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a new request from the ip, returns false if it already has too
+    /// many requests in flight
+    fn try_acquire(&self, ip: IpAddr) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// The number of requests in flight across all ips
+    fn total(&self) -> usize {
+        self.counts.lock().unwrap().values().sum()
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Counts how many attempts each IP made in the current window
+struct AttemptWindow {
+    max_per_window: u32,
+    attempts: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl AttemptWindow {
+    fn new(max_per_window: u32) -> Self {
+        AttemptWindow {
+            max_per_window,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an attempt from the ip at `now`, returns false if it already
+    /// used up its attempts for the window
+    fn try_record(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() >= PASSWORD_ATTEMPT_PRUNE_SIZE {
+            attempts.retain(|_, (started, _)| now - *started < PASSWORD_ATTEMPT_WINDOW);
+        }
+        let (started, count) = attempts.entry(ip).or_insert((now, 0));
+        if now - *started >= PASSWORD_ATTEMPT_WINDOW {
+            *started = now;
+            *count = 0;
+        }
+        if *count >= self.max_per_window {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+/// Work that's running by key, so asking for work that's already running
+/// waits for its result instead of running it again
+struct Coalescer<T> {
+    running: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T: Clone> Coalescer<T> {
+    fn new() -> Self {
+        Coalescer {
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `work`, or wait for the result of the work already running for
+    /// `key`. If the request running it goes away, one of the waiting ones
+    /// runs its own `work` instead.
+    async fn run<F: Future<Output = T>>(&self, key: &str, work: F) -> T {
+        let cell = self
+            .running
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let result = cell.get_or_init(|| work).await.clone();
+        // forget it once it's done, so the next request starts over
+        let mut running = self.running.lock().unwrap();
+        if running
+            .get(key)
+            .is_some_and(|running| Arc::ptr_eq(running, &cell))
+        {
+            running.remove(key);
+        }
+        result
+    }
+}
+
+/// Counts wrong passwords in a row for each protected image, locking it for
+/// longer and longer once there are too many
+struct FailureBackoff {
+    failures_before_lockout: u32,
+    /// The number of failures in a row and when the last one was
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl FailureBackoff {
+    fn new(failures_before_lockout: u32) -> Self {
+        FailureBackoff {
+            failures_before_lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long the image is locked for after `failures` in a row, doubling
+    /// from a second with every failure past failures_before_lockout
+    fn lockout(&self, failures: u32) -> Duration {
+        if failures < self.failures_before_lockout {
+            return Duration::ZERO;
+        }
+        let doublings = failures - self.failures_before_lockout;
+        Duration::from_secs(1u64.checked_shl(doublings).unwrap_or(u64::MAX))
+            .min(MAX_PASSWORD_LOCKOUT)
+    }
+
+    /// How much longer the image is locked at `now`, None if it isn't
+    fn locked_for(&self, key: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let (count, last) = failures.get(key)?;
+        let until = *last + self.lockout(*count);
+        (until > now).then(|| until - now)
+    }
+
+    /// Count a wrong password for the image, returns the failures in a row
+    fn record_failure(&self, key: &str, now: Instant) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= PASSWORD_ATTEMPT_PRUNE_SIZE {
+            failures.retain(|_, (_, last)| now - *last < PASSWORD_FAILURE_MEMORY);
+        }
+        let (count, last) = failures.entry(key.to_string()).or_insert((0, now));
+        if now - *last >= PASSWORD_FAILURE_MEMORY {
+            *count = 0;
+        }
+        *count += 1;
+        *last = now;
+        *count
+    }
+
+    fn record_success(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+/// Parse the resident set size in bytes out of the contents of
+/// /proc/self/status
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// How much memory the process is using, None if it can't be read (like on
+/// platforms without procfs)
+fn current_rss() -> Option<u64> {
+    parse_vm_rss(&std::fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// Why we're under too much load to take new work, if we are
+fn pressure_reason() -> Option<String> {
+    if let Some(max) = *MAX_MEMORY_BYTES {
+        if let Some(rss) = current_rss().filter(|rss| *rss > max) {
+            return Some(format!("using {} MiB of memory", rss / 1024 / 1024));
+        }
+    }
+    if let Some(max) = *MAX_UPLOADS_IN_FLIGHT {
+        let total = UPLOADS_IN_FLIGHT.total();
+        if total >= max {
+            return Some(format!("{} uploads in flight", total));
+        }
+    }
+    None
+}
+
+/// Wait until we're no longer over MAX_MEMORY_MEGABYTES or
+/// MAX_UPLOADS_IN_FLIGHT, so background work pauses instead of getting the
+/// whole service killed
+pub async fn wait_for_capacity() {
+    let mut paused = false;
+    while let Some(reason) = pressure_reason() {
+        if !paused {
+            warn!("Pausing background work: {}", reason);
+            paused = true;
+        }
+        tokio::time::sleep(PRESSURE_POLL_INTERVAL).await;
+    }
+    if paused {
+        info!("Resuming background work");
+    }
+}
+
+/// A request guard for uploads that fails with 503 Service Unavailable while
+/// we're over MAX_MEMORY_MEGABYTES or MAX_UPLOADS_IN_FLIGHT, and with 429 Too
+/// Many Requests if the client already has MAX_CONCURRENT_UPLOADS_PER_IP
+/// uploads in flight. The slot is given back when the guard is dropped at the
+/// end of the request.
+pub struct UploadPermit {
+    ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadPermit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if let Some(reason) = pressure_reason() {
+            let total = SHED_UPLOADS.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("Shed upload: {} ({} so far)", reason, total);
+            return request::Outcome::Error((Status::ServiceUnavailable, ()));
+        }
+        let ip = crate::proxy::client_ip(req);
+        if let Some(ip) = ip {
+            if !UPLOADS_IN_FLIGHT.try_acquire(ip) {
+                return request::Outcome::Error((Status::TooManyRequests, ()));
+            }
+        }
+        request::Outcome::Success(UploadPermit { ip })
+    }
+}
+
+impl Drop for UploadPermit {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            UPLOADS_IN_FLIGHT.release(ip);
+        }
+    }
+}
+
+/// Where a password attempt came from, so it can be limited with
+/// `try_check_password`
+pub struct PasswordAttempt {
+    ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PasswordAttempt {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(PasswordAttempt {
+            ip: crate::proxy::client_ip(req),
+        })
+    }
+}
+
+impl PasswordAttempt {
+    /// Count an attempt at the password of the image and take one of the
+    /// MAX_CONCURRENT_PASSWORD_CHECKS slots, hold on to the permit while
+    /// checking the password. None means the client should get 429 Too Many
+    /// Requests, because the image is locked after too many wrong passwords,
+    /// the client used up its PASSWORD_ATTEMPTS_PER_MINUTE or too many checks
+    /// are running.
+    pub fn try_start(&self, image_id: &str) -> Option<SemaphorePermit<'static>> {
+        if let Some(locked_for) = PASSWORD_FAILURES.locked_for(image_id, Instant::now()) {
+            info!(
+                "Password attempt for locked image {} from {:?}, locked for {}s",
+                image_id,
+                self.ip,
+                locked_for.as_secs()
+            );
+            return None;
+        }
+        if let Some(ip) = self.ip {
+            if !PASSWORD_ATTEMPTS.try_record(ip, Instant::now()) {
+                info!("Too many password attempts from {}", ip);
+                return None;
+            }
+        }
+        PASSWORD_CHECKS.try_acquire().ok()
+    }
+
+    /// Record whether the password was right, wrong ones count towards
+    /// locking the image
+    pub fn finish(&self, image_id: &str, is_correct: bool) {
+        if is_correct {
+            PASSWORD_FAILURES.record_success(image_id);
+            return;
+        }
+        let now = Instant::now();
+        let failures = PASSWORD_FAILURES.record_failure(image_id, now);
+        match PASSWORD_FAILURES.locked_for(image_id, now) {
+            Some(locked_for) => warn!(
+                "Wrong password for image {} from {:?}, {} in a row, locked for {}s",
+                image_id,
+                self.ip,
+                failures,
+                locked_for.as_secs()
+            ),
+            None => info!(
+                "Wrong password for image {} from {:?}, {} in a row",
+                image_id, self.ip, failures
+            ),
+        }
+    }
+}
+
+/// Run a conversion in one of the MAX_CONCURRENT_TRANSCODES slots, failing
+/// with 503 Service Unavailable if they're all taken. Requests for a
+/// conversion with the same `key` that's already running wait for it instead.
+pub async fn run_transcode<F>(key: &str, transcode: F) -> Result<Vec<u8>, Status>
+where
+    F: Future<Output = Result<Vec<u8>, Status>>,
+{
+    RUNNING_TRANSCODES
+        .run(key, async {
+            let _permit = TRANSCODES
+                .try_acquire()
+                .map_err(|_| Status::ServiceUnavailable)?;
+            transcode.await
+        })
+        .await
+}
+
+/// Count and log an upload that we gave up on
+fn record_timed_out_upload(reason: &str) -> String {
+    let total = TIMED_OUT_UPLOADS.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!("Dropped upload: {} ({} so far)", reason, total);
+    format!("Upload {}", reason)
+}
+
+/// Whether a client that sent `bytes` in `elapsed` is sending slower than
+/// `min_rate` bytes per second
+fn is_too_slow(bytes: u64, elapsed: Duration, min_rate: u64) -> bool {
+    elapsed > MIN_RATE_GRACE_PERIOD && (bytes as f64) < min_rate as f64 * elapsed.as_secs_f64()
+}
+
+/// An upload body we stopped reading, with the status to answer with
+#[derive(Debug)]
+pub struct UploadReadError(pub Status, pub String);
+
+impl std::fmt::Display for UploadReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl std::error::Error for UploadReadError {}
+
+/// Reads a request body in chunks, giving up with 408 Request Timeout if it
+/// takes longer than UPLOAD_TIMEOUT_SECONDS or trickles in slower than
+/// MIN_UPLOAD_BYTES_PER_SECOND, and with 413 Payload Too Large if it's bigger
+/// than `limit`
+struct UploadBody<'r> {
+    stream: DataStream<'r>,
+    limit: ByteUnit,
+    started: Instant,
+    deadline: Instant,
+    received: u64,
+}
+
+impl<'r> UploadBody<'r> {
+    fn new(data: Data<'r>, limit: ByteUnit) -> Self {
+        let started = Instant::now();
+        UploadBody {
+            // one byte more than allowed, to tell a body that's exactly at
+            // the limit apart from one that was cut off
+            stream: data.open(limit + 1),
+            limit,
+            started,
+            deadline: started + *UPLOAD_TIMEOUT,
+            received: 0,
+        }
+    }
+
+    /// The next chunk of the body, None once it's all been read
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, UploadReadError> {
+        let mut buf = vec![0; 64 * 1024];
+        let read = timeout_at(self.deadline, self.stream.read(&mut buf))
+            .await
+            .map_err(|_| {
+                UploadReadError(Status::RequestTimeout, record_timed_out_upload("timed out"))
+            })?
+            .map_err(|_| {
+                UploadReadError(
+                    Status::BadRequest,
+                    "Failed to read request body".to_string(),
+                )
+            })?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.received += read as u64;
+        if self.received > self.limit.as_u64() {
+            return Err(UploadReadError(
+                Status::PayloadTooLarge,
+                "Upload is too large".to_string(),
+            ));
+        }
+        if is_too_slow(
+            self.received,
+            self.started.elapsed(),
+            *MIN_UPLOAD_BYTES_PER_SECOND,
+        ) {
+            return Err(UploadReadError(
+                Status::RequestTimeout,
+                record_timed_out_upload("was sent too slowly"),
+            ));
+        }
+        buf.truncate(read);
+        Ok(Some(buf))
+    }
+}
+
+/// Read a request body up to `limit` with the same checks as `UploadBody`
+pub async fn read_upload_body(
+    data: Data<'_>,
+    limit: ByteUnit,
+) -> Result<Vec<u8>, (Status, String)> {
+    let mut upload = UploadBody::new(data, limit);
+    let mut body = Vec::new();
+    while let Some(chunk) = upload
+        .next_chunk()
+        .await
+        .map_err(|UploadReadError(status, e)| (status, e))?
+    {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// A request body up to `limit` as a stream of chunks with the same checks
+/// as `UploadBody`, for parsers that read it as it arrives (like multipart)
+pub fn upload_body_stream(
+    data: Data<'_>,
+    limit: ByteUnit,
+) -> impl Stream<Item = Result<Bytes, UploadReadError>> + Send + '_ {
+    futures::stream::unfold(Some(UploadBody::new(data, limit)), |upload| async move {
+        let mut upload = upload?;
+        match upload.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(Bytes::from(chunk)), Some(upload))),
+            Ok(None) => None,
+            // nothing more is read after an error
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn is_too_slow_has_grace_period() {
+        assert!(!is_too_slow(0, Duration::from_secs(1), 1024));
+        assert!(is_too_slow(0, Duration::from_secs(6), 1024));
+    }
+    #[test]
+    fn is_too_slow_checks_average_rate() {
+        assert!(!is_too_slow(10 * 1024, Duration::from_secs(10), 1024));
+        assert!(is_too_slow(9 * 1024, Duration::from_secs(10), 1024));
+    }
+    #[test]
+    fn in_flight_limits_per_ip() {
+        let in_flight = InFlight::new(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(in_flight.try_acquire(a));
+        assert!(in_flight.try_acquire(a));
+        assert!(!in_flight.try_acquire(a));
+        assert!(in_flight.try_acquire(b));
+        in_flight.release(a);
+        assert!(in_flight.try_acquire(a));
+    }
+    #[test]
+    fn in_flight_counts_total() {
+        let in_flight = InFlight::new(2);
+        assert!(in_flight.try_acquire("10.0.0.1".parse().unwrap()));
+        assert!(in_flight.try_acquire("10.0.0.1".parse().unwrap()));
+        assert!(in_flight.try_acquire("10.0.0.2".parse().unwrap()));
+        assert_eq!(in_flight.total(), 3);
+    }
+    #[test]
+    fn parse_vm_rss_reads_kilobytes() {
+        let status = "Name:\timage-host-api\nVmPeak:\t  2048 kB\nVmRSS:\t  1024 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(1024 * 1024));
+        assert_eq!(parse_vm_rss("Name:\timage-host-api\n"), None);
+    }
+    #[test]
+    fn attempt_window_limits_per_ip() {
+        let window = AttemptWindow::new(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(window.try_record(a, now));
+        assert!(window.try_record(a, now));
+        assert!(!window.try_record(a, now));
+        assert!(window.try_record(b, now));
+        assert!(window.try_record(a, now + PASSWORD_ATTEMPT_WINDOW));
+    }
+    #[test]
+    fn attempt_window_prunes_old_ips() {
+        let window = AttemptWindow::new(1);
+        let now = Instant::now();
+        for i in 0..PASSWORD_ATTEMPT_PRUNE_SIZE as u32 {
+            assert!(window.try_record(IpAddr::from(i.to_be_bytes()), now));
+        }
+        let later = now + PASSWORD_ATTEMPT_WINDOW;
+        assert!(window.try_record("10.0.0.1".parse().unwrap(), later));
+        assert_eq!(window.attempts.lock().unwrap().len(), 1);
+    }
+    #[test]
+    fn failure_backoff_locks_and_doubles() {
+        let backoff = FailureBackoff::new(2);
+        let now = Instant::now();
+        assert_eq!(backoff.record_failure("a", now), 1);
+        assert_eq!(backoff.locked_for("a", now), None);
+        backoff.record_failure("a", now);
+        assert_eq!(backoff.locked_for("a", now), Some(Duration::from_secs(1)));
+        backoff.record_failure("a", now);
+        assert_eq!(backoff.locked_for("a", now), Some(Duration::from_secs(2)));
+        assert_eq!(backoff.locked_for("b", now), None);
+        assert_eq!(backoff.lockout(100), MAX_PASSWORD_LOCKOUT);
+        backoff.record_success("a");
+        assert_eq!(backoff.locked_for("a", now), None);
+    }
+    #[test]
+    fn failure_backoff_forgets_old_failures() {
+        let backoff = FailureBackoff::new(2);
+        let now = Instant::now();
+        backoff.record_failure("a", now);
+        assert_eq!(
+            backoff.record_failure("a", now + PASSWORD_FAILURE_MEMORY),
+            1
+        );
+    }
+    #[rocket::async_test]
+    async fn coalescer_runs_work_once() {
+        let coalescer = Coalescer::new();
+        let runs = AtomicU64::new(0);
+        let work = || async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            7
+        };
+        let (a, b) = tokio::join!(coalescer.run("a", work()), coalescer.run("a", work()));
+        assert_eq!((a, b), (7, 7));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert!(coalescer.running.lock().unwrap().is_empty());
+        coalescer.run("a", work()).await;
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+    #[test]
+    fn in_flight_forgets_idle_ips() {
+        let in_flight = InFlight::new(1);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(in_flight.try_acquire(a));
+        in_flight.release(a);
+        assert!(in_flight.counts.lock().unwrap().is_empty());
+    }
+}
@@ -5,41 +5,167 @@ extern crate rocket;
 extern crate lazy_static;
 
 mod background_optimization;
+mod cache;
+mod chaos;
+mod config;
 mod db;
+mod db_pool;
 mod encoding;
+mod geoip;
+mod limits;
+mod placeholder;
+mod proxy;
+mod quality;
+mod raw_multipart;
+#[cfg(test)]
+mod test_support;
 mod util;
 
-use background_optimization::{optimize_image_and_update, optimize_images_from_database};
+use background_optimization::{optimize_image_by_id, optimize_images_from_database};
 use base64::{engine::general_purpose, Engine as _};
 use dotenv::dotenv;
 use log::info;
-use rocket::data::ToByteUnit;
+use rocket::data::{Limits, ToByteUnit};
 use rocket::form::Form;
-use rocket::http::{ContentType, Header, Status};
-use rocket::response::{status::Custom, Redirect};
-use rocket::serde::json::serde_json;
+use rocket::http::{ContentType, Cookie, CookieJar, Header, SameSite, Status};
+use rocket::request::{self, FromParam, FromRequest};
+use rocket::response::{self, content::RawHtml, status::Custom, Redirect, Responder};
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::{Data, State};
-use rocket_multipart_form_data::{
-    mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
-};
-use std::io::Cursor;
+use rocket::{Data, Request, Response, State};
+use std::path::PathBuf;
 use tokio::{join, task};
 use util::ImageId;
 
 lazy_static! {
     static ref HOST: String = std::env::var("HOST").unwrap_or("i.dishis.tech".to_string());
+    /// If set, a second plain HTTP listener is started on this port that
+    /// redirects everything to https://HOST
+    static ref HTTPS_REDIRECT_PORT: Option<u16> = std::env::var("HTTPS_REDIRECT_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok());
+    /// The contents of robots.txt, read from the file at ROBOTS_TXT_PATH
+    static ref ROBOTS_TXT: String = std::env::var("ROBOTS_TXT_PATH")
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .unwrap_or("User-agent: *\nAllow: /\n".to_string());
+    /// Whether to store the IP, user agent and client id of uploads
+    static ref TRACK_UPLOAD_SOURCE: bool = std::env::var("TRACK_UPLOAD_SOURCE")
+        .map(|track| util::parse_bool_field(&track))
+        .unwrap_or(false);
+    /// Whether POST /api/debug/process is available
+    static ref ENABLE_DEBUG_ENDPOINT: bool = std::env::var("ENABLE_DEBUG_ENDPOINT")
+        .map(|enable| util::parse_bool_field(&enable))
+        .unwrap_or(false);
+    /// Whether GET /health/canary is available
+    static ref ENABLE_CANARY_ENDPOINT: bool = std::env::var("ENABLE_CANARY_ENDPOINT")
+        .map(|enable| util::parse_bool_field(&enable))
+        .unwrap_or(false);
+    /// Where multipart file uploads are written while they're being received,
+    /// they're deleted once the request is done
+    static ref UPLOAD_SPOOL_DIR: PathBuf = std::env::var("UPLOAD_SPOOL_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir());
 }
 
 #[derive(FromForm)]
 struct UrlencodedUpload {
     image: String,
+    noai: Option<bool>,
+    password: Option<String>,
+    cache: Option<String>,
+    license: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct ApiUploadRequest {
     base64: Option<String>,
     url: Option<String>,
+    #[serde(flatten)]
+    options: UploadOptions,
+}
+
+/// The optional settings that can be sent along with any kind of upload
+#[derive(Default, Deserialize)]
+struct UploadOptions {
+    noai: Option<bool>,
+    password: Option<String>,
+    cache: Option<String>,
+    license: Option<String>,
+}
+
+impl UploadOptions {
+    /// Read the options from the text fields of a multipart form
+    fn from_multipart(form: &raw_multipart::UploadForm) -> Self {
+        let text = |name: &str| form.texts.get(name).cloned();
+        UploadOptions {
+            noai: text("noai").map(|noai| util::parse_bool_field(&noai)),
+            password: text("password"),
+            cache: text("cache"),
+            license: text("license"),
+        }
+    }
+
+    fn into_settings(
+        self,
+        source: UploadSourceGuard,
+    ) -> Result<db::ImageSettings, Custom<Json<ApiErrorResponse>>> {
+        let password_hash = match self.password.filter(|password| !password.is_empty()) {
+            Some(password) => Some(
+                util::hash_password(&password)
+                    .map_err(|e| create_error(Status::InternalServerError, &e))?,
+            ),
+            None => None,
+        };
+        let cache_policy = match self.cache.filter(|cache| !cache.is_empty()) {
+            Some(cache) => Some(cache::CachePolicy::parse(&cache).ok_or_else(|| {
+                create_error(
+                    Status::BadRequest,
+                    "cache must be \"revalidate\", \"immutable\" or a number of seconds",
+                )
+            })?),
+            None => None,
+        };
+        let license = match self.license.filter(|license| !license.is_empty()) {
+            Some(license) => Some(util::parse_license(&license).ok_or_else(|| {
+                create_error(
+                    Status::BadRequest,
+                    "license must be a Creative Commons license like \"CC-BY-4.0\", \"all-rights-reserved\" or a URL",
+                )
+            })?),
+            None => None,
+        };
+        Ok(db::ImageSettings {
+            noai: self.noai.unwrap_or(false),
+            password_hash,
+            source: source.0,
+            cache_policy,
+            license,
+        })
+    }
+}
+
+/// Where an upload came from, or None if TRACK_UPLOAD_SOURCE is disabled
+struct UploadSourceGuard(Option<db::UploadSource>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadSourceGuard {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if !*TRACK_UPLOAD_SOURCE {
+            return request::Outcome::Success(UploadSourceGuard(None));
+        }
+        let header = |name: &str| {
+            req.headers()
+                .get_one(name)
+                .map(|value| value.chars().take(256).collect::<String>())
+        };
+        request::Outcome::Success(UploadSourceGuard(Some(db::UploadSource {
+            ip: proxy::client_ip(req).map(|ip| ip.to_string()),
+            user_agent: header("User-Agent"),
+            client: header("X-Client-Id"),
+        })))
+    }
 }
 
 #[derive(Serialize)]
@@ -63,6 +189,10 @@ struct ApiImageData {
     size: String,
     time: String,
     expiration: String,
+    /// From 0 (blurry or badly exposed) to 1
+    quality: String,
+    /// Empty if the uploader didn't pick a license
+    license: String,
     image: ApiImageVariant,
     thumb: ApiImageVariant,
     medium: ApiImageVariant,
@@ -76,6 +206,200 @@ struct ApiResponse {
     status: u16,
 }
 
+#[derive(Serialize)]
+struct ApiEmbed {
+    domain: String,
+    /// Unix timestamp of the start of the day (UTC)
+    day: i64,
+    count: i64,
+}
+
+#[derive(Serialize)]
+struct ApiEmbedsResponse {
+    data: Vec<ApiEmbed>,
+    success: bool,
+    status: u16,
+}
+
+/// The most images that can be asked for in one metadata batch
+const MAX_METADATA_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct ApiMetadataBatchRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ApiImageMetadata {
+    url: String,
+    thumb_url: String,
+    width: i64,
+    height: i64,
+    mime: String,
+    /// In bytes
+    size: i64,
+    /// Unix timestamp of when the image was uploaded
+    time: i64,
+    /// From 0 (blurry or badly exposed) to 1
+    quality: Option<f64>,
+    license: Option<String>,
+    noai: bool,
+    password_protected: bool,
+}
+
+/// The metadata of one of the requested images, or why it couldn't be found
+#[derive(Serialize)]
+struct ApiMetadataBatchItem {
+    id: String,
+    metadata: Option<ApiImageMetadata>,
+    error: Option<String>,
+    /// The image is password protected and wasn't unlocked, so there's no
+    /// metadata
+    locked: bool,
+}
+
+#[derive(Serialize)]
+struct ApiMetadataBatchResponse {
+    data: Vec<ApiMetadataBatchItem>,
+    success: bool,
+    status: u16,
+}
+
+#[derive(Deserialize)]
+struct ApiShareLinkRequest {
+    /// Required if the image is password protected
+    password: Option<String>,
+    max_views: Option<u32>,
+    /// How many seconds until the link stops working
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ApiShareLink {
+    url: String,
+    max_views: Option<u32>,
+    /// Unix timestamp of when the link stops working
+    expiration: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ApiShareLinkResponse {
+    data: ApiShareLink,
+    success: bool,
+    status: u16,
+}
+
+#[derive(Serialize)]
+struct ApiSrcsetVariant {
+    url: String,
+    width: u32,
+    height: u32,
+    mime: String,
+}
+
+#[derive(Serialize)]
+struct ApiSrcset {
+    /// Ready to use value for the srcset attribute
+    srcset: String,
+    img: String,
+    picture: String,
+    variants: Vec<ApiSrcsetVariant>,
+}
+
+#[derive(Serialize)]
+struct ApiSrcsetResponse {
+    data: ApiSrcset,
+    success: bool,
+    status: u16,
+}
+
+#[derive(Serialize)]
+struct ApiDebugStage {
+    name: &'static str,
+    millis: u64,
+}
+
+/// Times the stages of a request, for the debug and canary endpoints
+struct StageTimer {
+    stages: Vec<ApiDebugStage>,
+    stage_started: std::time::Instant,
+}
+
+impl StageTimer {
+    fn new() -> Self {
+        StageTimer {
+            stages: Vec::new(),
+            stage_started: std::time::Instant::now(),
+        }
+    }
+
+    /// Record how long the stage that just finished took and start the next
+    fn end(&mut self, name: &'static str) {
+        self.stages.push(ApiDebugStage {
+            name,
+            millis: self.stage_started.elapsed().as_millis() as u64,
+        });
+        self.stage_started = std::time::Instant::now();
+    }
+}
+
+#[derive(Serialize)]
+struct ApiCanaryResult {
+    healthy: bool,
+    /// The stage that failed, if any
+    failed_stage: Option<&'static str>,
+    error: Option<String>,
+    stages: Vec<ApiDebugStage>,
+}
+
+#[derive(Serialize)]
+struct ApiCanaryResponse {
+    data: ApiCanaryResult,
+    success: bool,
+    status: u16,
+}
+
+#[derive(Serialize)]
+struct ApiDatabasePoolResponse {
+    data: db_pool::PoolSnapshot,
+    uploads: limits::UploadSnapshot,
+    success: bool,
+    status: u16,
+}
+
+#[derive(Serialize)]
+struct ApiDebugVariant {
+    name: &'static str,
+    max_size: Option<u32>,
+    optimize_png: bool,
+    width: u32,
+    height: u32,
+    mime: String,
+    size: usize,
+}
+
+#[derive(Serialize)]
+struct ApiDebugTrace {
+    received_bytes: usize,
+    /// Guessed from the magic bytes
+    detected_mime: Option<String>,
+    decoded_format: Option<String>,
+    color_type: String,
+    width: u32,
+    height: u32,
+    quality: quality::QualityScore,
+    /// What would be stored on upload and after background optimization
+    variants: Vec<ApiDebugVariant>,
+    stages: Vec<ApiDebugStage>,
+}
+
+#[derive(Serialize)]
+struct ApiDebugResponse {
+    data: ApiDebugTrace,
+    success: bool,
+    status: u16,
+}
+
 #[derive(Serialize)]
 struct ApiErrorResponse {
     error: String,
@@ -121,11 +445,12 @@ fn create_error(status: Status, message: &str) -> Custom<Json<ApiErrorResponse>>
 }
 
 fn mime_to_extension(mime_type: &str) -> &str {
-    mime_type.split('/').last().unwrap_or("jpg")
+    mime_type.split('/').next_back().unwrap_or("jpg")
 }
 
 async fn process_text_upload(
     mut text_value: String,
+    settings: db::ImageSettings,
     images_collection: &mongodb::Collection<mongodb::bson::Document>,
 ) -> Result<Json<ApiResponse>, Custom<Json<ApiErrorResponse>>> {
     text_value = text_value.trim().to_string();
@@ -134,7 +459,7 @@ async fn process_text_upload(
         let (image_bytes, ct) = download_image_from_url(&text_value)
             .await
             .map_err(|e| create_error(Status::BadRequest, &e))?;
-        return process_and_respond(image_bytes, &ct, images_collection).await;
+        return process_and_respond(image_bytes, &ct, settings, images_collection).await;
     }
 
     if let Some(idx) = text_value.find(',') {
@@ -152,12 +477,13 @@ async fn process_text_upload(
         )
     })?;
 
-    process_and_respond(image_bytes, kind.mime_type(), images_collection).await
+    process_and_respond(image_bytes, kind.mime_type(), settings, images_collection).await
 }
 
 async fn process_and_respond(
     image_bytes: Vec<u8>,
     content_type_string: &str,
+    settings: db::ImageSettings,
     images_collection: &mongodb::Collection<mongodb::bson::Document>,
 ) -> Result<Json<ApiResponse>, Custom<Json<ApiErrorResponse>>> {
     if image_bytes.is_empty() {
@@ -180,16 +506,18 @@ async fn process_and_respond(
         )
     })?;
 
-    let (encoded_image_result, encoded_thumbnail_result, image_id_result) = join!(
+    let quality_image = decoded_image.clone();
+    let (encoded_image_result, encoded_thumbnail_result, image_id_result, quality_result) = join!(
         encoding::from_image(decoded_image.clone(), encoding::FromImageOptions::default()),
         encoding::from_image(
             decoded_image,
             encoding::FromImageOptions {
-                max_size: Some(128),
+                max_size: Some(encoding::THUMBNAIL_SIZE),
                 ..encoding::FromImageOptions::default()
             }
         ),
-        db::generate_image_id(images_collection)
+        db::generate_image_id(images_collection),
+        task::spawn_blocking(move || quality::assess(&quality_image))
     );
 
     let encoded_image =
@@ -198,6 +526,7 @@ async fn process_and_respond(
         encoded_thumbnail_result.map_err(|e| create_error(Status::InternalServerError, &e))?;
     let image_id =
         image_id_result.map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?;
+    let quality = quality_result.ok();
 
     let insert_result = db::insert_image(
         images_collection,
@@ -209,6 +538,8 @@ async fn process_and_respond(
             thumbnail_content_type: &encoded_thumbnail.content_type,
             size: encoded_image.size,
             optim_level: 0,
+            settings: &settings,
+            quality,
         },
     )
     .await;
@@ -218,10 +549,12 @@ async fn process_and_respond(
 
     info!("Successfully uploaded image {}", &image_id);
 
-    let doc_for_bg = inserted_doc.clone();
+    // only the id is moved into the task, so uploads waiting for capacity
+    // don't keep the image in memory
+    let id_for_bg = image_id.clone();
     let owned_images_collection = images_collection.clone();
     task::spawn(async move {
-        optimize_image_and_update(&owned_images_collection, &doc_for_bg)
+        optimize_image_by_id(&owned_images_collection, &id_for_bg)
             .await
             .ok();
     });
@@ -235,8 +568,14 @@ async fn process_and_respond(
         / 1000;
     let image_ext = mime_to_extension(&encoded_image.content_type);
     let thumb_ext = mime_to_extension(&encoded_thumbnail.content_type);
-    let image_url = format!("{}/i/{}", base_url, id_str);
-    let thumb_url = format!("{}/i/{}/thumb", base_url, id_str);
+    // password protected images are only served from /p/
+    let route = if settings.password_hash.is_some() {
+        "p"
+    } else {
+        "i"
+    };
+    let image_url = format!("{}/{}/{}", base_url, route, id_str);
+    let thumb_url = format!("{}/{}/{}/thumb", base_url, route, id_str);
 
     Ok(Json(ApiResponse {
         data: ApiImageData {
@@ -250,6 +589,10 @@ async fn process_and_respond(
             size: encoded_image.data.len().to_string(),
             time: creation_time.to_string(),
             expiration: "0".to_string(),
+            quality: quality
+                .map(|quality| format!("{:.2}", quality.score))
+                .unwrap_or_default(),
+            license: settings.license.clone().unwrap_or_default(),
             delete_url: format!("{}/delete/placeholder", image_url),
             image: ApiImageVariant {
                 filename: format!("{}.{}", id_str, image_ext),
@@ -282,6 +625,71 @@ async fn process_and_respond(
 #[response(status = 200)]
 struct HtmlResponder(&'static str, Header<'static>);
 
+#[derive(Responder)]
+#[response(status = 429)]
+struct TooManyRequestsResponder(Json<ApiErrorResponse>, Header<'static>);
+
+#[catch(429)]
+fn too_many_requests() -> TooManyRequestsResponder {
+    TooManyRequestsResponder(
+        create_error(
+            Status::TooManyRequests,
+            "Too many uploads in progress, try again later.",
+        )
+        .1,
+        Header::new("Retry-After", limits::RETRY_AFTER_SECONDS.to_string()),
+    )
+}
+
+#[derive(Responder)]
+#[response(status = 503)]
+struct ServiceUnavailableResponder(Json<ApiErrorResponse>, Header<'static>);
+
+#[catch(503)]
+fn service_unavailable() -> ServiceUnavailableResponder {
+    ServiceUnavailableResponder(
+        create_error(
+            Status::ServiceUnavailable,
+            "The server is under heavy load, try again later.",
+        )
+        .1,
+        Header::new("Retry-After", limits::RETRY_AFTER_SECONDS.to_string()),
+    )
+}
+
+#[derive(Responder)]
+enum PlaceholderOrError {
+    Placeholder(Custom<placeholder::PlaceholderResponder>),
+    Error(Custom<Json<ApiErrorResponse>>),
+}
+
+#[catch(404)]
+fn not_found(req: &Request) -> PlaceholderOrError {
+    match placeholder::for_request(req, Status::NotFound) {
+        Some(placeholder) => PlaceholderOrError::Placeholder(placeholder),
+        None => PlaceholderOrError::Error(create_error(Status::NotFound, "Not found")),
+    }
+}
+
+#[catch(410)]
+fn gone(req: &Request) -> PlaceholderOrError {
+    match placeholder::for_request(req, Status::Gone) {
+        Some(placeholder) => PlaceholderOrError::Placeholder(placeholder),
+        None => PlaceholderOrError::Error(create_error(
+            Status::Gone,
+            "This image is no longer available.",
+        )),
+    }
+}
+
+#[catch(451)]
+fn unavailable_for_legal_reasons() -> Custom<Json<ApiErrorResponse>> {
+    create_error(
+        Status::UnavailableForLegalReasons,
+        "Uploads are not available in your region.",
+    )
+}
+
 #[get("/")]
 fn index() -> HtmlResponder {
     HtmlResponder(
@@ -292,18 +700,28 @@ fn index() -> HtmlResponder {
 
 #[post("/api/upload", data = "<data>", format = "json", rank = 1)]
 async fn api_upload_json(
-    data: Json<ApiUploadRequest>,
+    _region: geoip::UploadRegionCheck,
+    _permit: limits::UploadPermit,
+    source: UploadSourceGuard,
+    body_limits: &Limits,
+    data: Data<'_>,
     collections: &State<db::Collections>,
 ) -> Result<Json<ApiResponse>, Custom<Json<ApiErrorResponse>>> {
-    let req = data.into_inner();
+    // read it ourselves rather than with Json, so slow senders time out
+    let body = limits::read_upload_body(data, body_limits.get("json").unwrap_or(Limits::JSON))
+        .await
+        .map_err(|(status, e)| create_error(status, &e))?;
+    let req: ApiUploadRequest = rocket::serde::json::from_slice(&body)
+        .map_err(|e| create_error(Status::BadRequest, &format!("Invalid JSON: {}", e)))?;
+    let settings = req.options.into_settings(source)?;
     if let Some(b64) = req.base64 {
-        return process_text_upload(b64, &collections.images).await;
+        return process_text_upload(b64, settings, &collections.images).await;
     }
     if let Some(url) = req.url {
         let (image_bytes, ct) = download_image_from_url(&url)
             .await
             .map_err(|e| create_error(Status::BadRequest, &e))?;
-        return process_and_respond(image_bytes, &ct, &collections.images).await;
+        return process_and_respond(image_bytes, &ct, settings, &collections.images).await;
     }
     Err(create_error(
         Status::BadRequest,
@@ -311,54 +729,71 @@ async fn api_upload_json(
     ))
 }
 
-#[post("/api/upload", data = "<form>", format = "form", rank = 2)]
+#[post("/api/upload", data = "<data>", format = "form", rank = 2)]
 async fn api_upload_form(
-    form: Form<UrlencodedUpload>,
+    _region: geoip::UploadRegionCheck,
+    _permit: limits::UploadPermit,
+    source: UploadSourceGuard,
+    body_limits: &Limits,
+    data: Data<'_>,
     collections: &State<db::Collections>,
 ) -> Result<Json<ApiResponse>, Custom<Json<ApiErrorResponse>>> {
-    process_text_upload(form.into_inner().image, &collections.images).await
+    // read it ourselves rather than with Form, so slow senders time out
+    let body = limits::read_upload_body(data, body_limits.get("form").unwrap_or(Limits::FORM))
+        .await
+        .map_err(|(status, e)| create_error(status, &e))?;
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| create_error(Status::BadRequest, "Form isn't valid UTF-8"))?;
+    let form = Form::<UrlencodedUpload>::parse(body)
+        .map_err(|e| create_error(Status::BadRequest, &format!("Form parse error: {}", e)))?;
+    let settings = UploadOptions {
+        noai: form.noai,
+        password: form.password,
+        cache: form.cache,
+        license: form.license,
+    }
+    .into_settings(source)?;
+    process_text_upload(form.image, settings, &collections.images).await
 }
 
 #[post("/api/upload", data = "<data>", rank = 3)]
 async fn api_upload_fallback(
+    _region: geoip::UploadRegionCheck,
+    _permit: limits::UploadPermit,
+    source: UploadSourceGuard,
     content_type: &ContentType,
     data: Data<'_>,
     collections: &State<db::Collections>,
 ) -> Result<Json<ApiResponse>, Custom<Json<ApiErrorResponse>>> {
     // --- CASE 1: Proper multipart/form-data ---
     if content_type.is_form_data() {
-        let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-            MultipartFormDataField::file("image")
-                .content_type_by_string(Some(mime::STAR_STAR))
-                .unwrap(),
-            MultipartFormDataField::text("image"),
-        ]);
-
-        let form_data = MultipartFormData::parse(content_type, data, options)
-            .await
-            .map_err(|e| create_error(Status::BadRequest, &format!("Form parse error: {}", e)))?;
-
-        if let Some(files) = form_data.files.get("image") {
-            if let Some(file) = files.get(0) {
-                let image_bytes = tokio::fs::read(&file.path).await.map_err(|_| {
-                    create_error(Status::InternalServerError, "Could not read uploaded file")
-                })?;
-                let ct = file
-                    .content_type
-                    .as_ref()
-                    .map(|ct| ct.to_string())
-                    .unwrap_or_else(|| {
-                        infer::get(&image_bytes)
-                            .map(|k| k.mime_type().to_string())
-                            .unwrap_or_else(|| "application/octet-stream".to_string())
-                    });
-                return process_and_respond(image_bytes, &ct, &collections.images).await;
-            }
+        let boundary = content_type
+            .param("boundary")
+            .ok_or_else(|| create_error(Status::BadRequest, "Missing multipart boundary"))?;
+        // parsed as it arrives, so slow senders time out like other uploads
+        let form = raw_multipart::parse_upload_form(
+            limits::upload_body_stream(data, 20.megabytes()),
+            boundary,
+            &UPLOAD_SPOOL_DIR,
+        )
+        .await
+        .map_err(|(status, e)| create_error(status, &e))?;
+
+        let settings = UploadOptions::from_multipart(&form).into_settings(source)?;
+
+        if let Some(file) = &form.image_file {
+            let image_bytes = tokio::fs::read(&file.path).await.map_err(|_| {
+                create_error(Status::InternalServerError, "Could not read uploaded file")
+            })?;
+            let ct = file.content_type.clone().unwrap_or_else(|| {
+                infer::get(&image_bytes)
+                    .map(|k| k.mime_type().to_string())
+                    .unwrap_or_else(|| "application/octet-stream".to_string())
+            });
+            return process_and_respond(image_bytes, &ct, settings, &collections.images).await;
         }
-        if let Some(texts) = form_data.texts.get("image") {
-            if let Some(text_field) = texts.get(0) {
-                return process_text_upload(text_field.text.clone(), &collections.images).await;
-            }
+        if let Some(text) = form.texts.get("image") {
+            return process_text_upload(text.clone(), settings, &collections.images).await;
         }
         return Err(create_error(
             Status::BadRequest,
@@ -366,43 +801,29 @@ async fn api_upload_fallback(
         ));
     }
 
-    // --- CASE 2: Custom raw boundary parsing ---
-    let raw_body = data
-        .open(20.megabytes())
-        .into_bytes()
-        .await
-        .map_err(|_| create_error(Status::BadRequest, "Failed to read request body"))?
-        .into_inner();
-
-    let body_str = String::from_utf8_lossy(&raw_body);
-
-    if let Some(start) = body_str.find("------") {
-        let boundary_line = body_str.lines().next().unwrap_or("").trim().to_string();
+    let settings = db::ImageSettings {
+        source: source.0,
+        ..db::ImageSettings::default()
+    };
 
-        let boundary = boundary_line.trim();
-        let parts: Vec<&str> = body_str.split(boundary).collect();
+    // --- CASE 2: Multipart without a form-data Content-Type ---
+    let raw_body = limits::read_upload_body(data, 20.megabytes())
+        .await
+        .map_err(|(status, e)| create_error(status, &e))?;
 
-        for part in parts {
-            if part.contains("Content-Disposition")
-                && part.contains("filename=")
-                && part.contains("Content-Type")
-            {
-                if let Some(idx) = part.find("\r\n\r\n") {
-                    let file_data = &part[idx + 4..];
-                    let file_bytes = file_data.as_bytes().to_vec();
-
-                    let ct = if let Some(ct_idx) = part.find("Content-Type:") {
-                        let line = part[ct_idx..].lines().next().unwrap_or("");
-                        line.replace("Content-Type:", "").trim().to_string()
-                    } else {
-                        infer::get(&file_bytes)
-                            .map(|k| k.mime_type().to_string())
-                            .unwrap_or_else(|| "application/octet-stream".to_string())
-                    };
-
-                    return process_and_respond(file_bytes, &ct, &collections.images).await;
-                }
-            }
+    let boundary = content_type
+        .param("boundary")
+        .map(|boundary| boundary.to_string())
+        .or_else(|| raw_multipart::sniff_boundary(&raw_body));
+    if let Some(boundary) = boundary {
+        // if there's no file in it, treat the body as raw binary below
+        if let Some(file) = raw_multipart::first_file(raw_body.clone(), &boundary).await {
+            let ct = file.content_type.unwrap_or_else(|| {
+                infer::get(&file.data)
+                    .map(|k| k.mime_type().to_string())
+                    .unwrap_or_else(|| "application/octet-stream".to_string())
+            });
+            return process_and_respond(file.data, &ct, settings, &collections.images).await;
         }
     }
 
@@ -415,21 +836,182 @@ async fn api_upload_fallback(
         .map(|kind| kind.mime_type().to_string())
         .unwrap_or_else(|| "application/octet-stream".to_string());
 
-    process_and_respond(raw_body, &ct, &collections.images).await
+    process_and_respond(raw_body, &ct, settings, &collections.images).await
 }
 
-#[derive(Responder)]
-#[response(status = 200)]
-struct ImageResponder(Vec<u8>, Header<'static>);
+struct ImageResponder {
+    data: Vec<u8>,
+    content_type: String,
+    cache_control: String,
+    /// Unquoted, changes whenever the stored image is re-encoded. Variants
+    /// like thumbnails add a suffix.
+    etag: String,
+    /// Set for HEAD requests, so the Content-Length is right without loading
+    /// the data
+    head_size: Option<usize>,
+    headers: Vec<Header<'static>>,
+}
 
-#[get("/i/<id>")]
-async fn view_image_route(
+impl ImageResponder {
+    fn new(doc: &mongodb::bson::Document, data: Vec<u8>, content_type: String) -> Self {
+        let mut headers = Vec::new();
+        if doc.get_bool("noai").unwrap_or(false) {
+            headers.push(Header::new("X-Robots-Tag", "noai, noindex"));
+        }
+        if let Ok(license) = doc.get_str("license") {
+            headers.push(Header::new("X-License", license.to_string()));
+        }
+        let optim_level = util::get_int(doc, "optim_level").unwrap_or(0);
+        let cache_policy = doc
+            .get_str("cache_policy")
+            .ok()
+            .and_then(cache::CachePolicy::parse)
+            .unwrap_or(*cache::DEFAULT_CACHE_POLICY)
+            .for_image(optim_level >= background_optimization::FINAL_OPTIM_LEVEL);
+        ImageResponder {
+            data,
+            content_type,
+            cache_control: cache_policy.header_value(is_password_protected(doc)),
+            etag: format!("{}-{}", doc.get_str("_id").unwrap_or_default(), optim_level),
+            head_size: None,
+            headers,
+        }
+    }
+
+    /// Mark this as a different variant of the stored image, like a
+    /// thumbnail, so it gets its own ETag
+    fn variant(mut self, name: &str) -> Self {
+        self.etag = format!("{}-{}", self.etag, name);
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ImageResponder {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let etag = format!("\"{}\"", self.etag);
+        let not_modified = req
+            .headers()
+            .get_one("If-None-Match")
+            .map(|if_none_match| util::etag_matches(if_none_match, &etag))
+            .unwrap_or(false);
+
+        let mut response = if not_modified {
+            let mut response = Response::build();
+            response.status(Status::NotModified);
+            response
+        } else if let Some(size) = self.head_size {
+            let mut response = Response::build();
+            response.sized_body(size, std::io::Cursor::new(Vec::new()));
+            response
+        } else {
+            Response::build_from(self.data.respond_to(req)?)
+        };
+        response.raw_header("Content-Type", self.content_type);
+        response.raw_header("Cache-Control", self.cache_control);
+        response.raw_header("ETag", etag);
+        for header in self.headers {
+            response.header(header);
+        }
+        response.ok()
+    }
+}
+
+/// How long JSON about an image can be cached for, it's short since things
+/// like embed counts change all the time
+const METADATA_MAX_AGE: u32 = 60;
+
+/// JSON sent with an ETag of its contents and a Cache-Control header. Clients
+/// that send a matching If-None-Match get 304 Not Modified instead.
+struct CachedJson<T> {
+    value: T,
+    /// Only the client can cache it, for things about password protected images
+    private: bool,
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for CachedJson<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body =
+            rocket::serde::json::to_string(&self.value).map_err(|_| Status::InternalServerError)?;
+        let etag = format!("\"{:016x}\"", util::fnv1a(body.as_bytes()));
+        let not_modified = req
+            .headers()
+            .get_one("If-None-Match")
+            .map(|if_none_match| util::etag_matches(if_none_match, &etag))
+            .unwrap_or(false);
+
+        let mut response = if not_modified {
+            let mut response = Response::build();
+            response.status(Status::NotModified);
+            response
+        } else {
+            Response::build_from((ContentType::JSON, body).respond_to(req)?)
+        };
+        let scope = if self.private { "private" } else { "public" };
+        response.raw_header("ETag", etag);
+        response.raw_header(
+            "Cache-Control",
+            format!("{}, max-age={}", scope, METADATA_MAX_AGE),
+        );
+        response.ok()
+    }
+}
+
+/// The external domain that a request was embedded from, if any
+struct EmbedReferrer(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for EmbedReferrer {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let domain = req
+            .headers()
+            .get_one("Referer")
+            .and_then(|referer| util::referrer_domain(referer, &HOST));
+        request::Outcome::Success(EmbedReferrer(domain))
+    }
+}
+
+/// Count the view in the background if the image was embedded elsewhere
+fn spawn_record_embed(collections: &db::Collections, id: &str, referrer: EmbedReferrer) {
+    if let EmbedReferrer(Some(domain)) = referrer {
+        let embeds_collection = collections.embeds.clone();
+        let image_id = ImageId(id.to_string());
+        task::spawn(async move {
+            db::record_embed(&embeds_collection, &image_id, &domain)
+                .await
+                .ok();
+        });
+    }
+}
+
+/// Whether the image can only be viewed at /p/<id> with a password
+fn is_password_protected(doc: &mongodb::bson::Document) -> bool {
+    doc.get_str("password_hash").is_ok()
+}
+
+/// Find an image document, or fail with 404 Not Found
+async fn find_image(
+    id: &str,
+    collections: &db::Collections,
+) -> Result<mongodb::bson::Document, Status> {
+    db::get_image(&collections.images, id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)
+}
+
+/// Respond with the full image and bump its last_seen in the background.
+/// Fails with 410 Gone if the stored image is unusable.
+fn serve_image(
+    doc: &mongodb::bson::Document,
     id: String,
-    collections: &State<db::Collections>,
-) -> Option<ImageResponder> {
-    let doc = db::get_image(&collections.images, &id).await.ok()??;
-    let data = doc.get_binary_generic("data").unwrap().clone();
-    let ct = doc.get_str("content_type").unwrap().to_string();
+    collections: &db::Collections,
+) -> Result<ImageResponder, Status> {
+    let (data, ct) = util::get_stored_data(doc, "data", "content_type").map_err(|e| {
+        error!("Can't serve image {}: {:?} data", id, e);
+        Status::Gone
+    })?;
 
     let images_collection = collections.images.clone();
     task::spawn(async move {
@@ -438,18 +1020,894 @@ async fn view_image_route(
             .ok();
     });
 
-    Some(ImageResponder(data, Header::new("Content-Type", ct)))
+    Ok(ImageResponder::new(doc, data, ct))
+}
+
+/// Respond with the thumbnail, regenerating it from the full image if it's
+/// missing or broken. Fails with 410 Gone if that isn't possible either.
+async fn serve_thumbnail(
+    doc: &mongodb::bson::Document,
+    id: &str,
+    collections: &db::Collections,
+) -> Result<ImageResponder, Status> {
+    let error = match util::get_stored_data(doc, "thumbnail_data", "thumbnail_content_type") {
+        Ok((data, ct)) => return Ok(ImageResponder::new(doc, data, ct).variant("thumb")),
+        Err(e) => e,
+    };
+    warn!("Regenerating thumbnail of {}: {:?} thumbnail", id, error);
+
+    let (data, ct) = util::get_stored_data(doc, "data", "content_type").map_err(|e| {
+        error!("Can't regenerate thumbnail of {}: {:?} data", id, e);
+        Status::Gone
+    })?;
+    let thumbnail = encoding::bytes_to_encoded(
+        data,
+        &ct,
+        encoding::FromImageOptions {
+            max_size: Some(encoding::THUMBNAIL_SIZE),
+            ..encoding::FromImageOptions::default()
+        },
+    )
+    .await
+    .map_err(|e| {
+        error!("Can't regenerate thumbnail of {}: {}", id, e);
+        Status::Gone
+    })?;
+
+    if let Err(e) = db::set_thumbnail(
+        &collections.images,
+        &ImageId(id.to_string()),
+        &thumbnail.data,
+        &thumbnail.content_type,
+    )
+    .await
+    {
+        error!("Failed to store regenerated thumbnail of {}: {}", id, e);
+    }
+    Ok(ImageResponder::new(doc, thumbnail.data, thumbnail.content_type).variant("thumb"))
+}
+
+/// An image id followed by a file extension, like "abc123.png"
+struct ImageFileName {
+    id: String,
+    extension: String,
+}
+
+impl<'a> FromParam<'a> for ImageFileName {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match param.rsplit_once('.') {
+            Some((id, extension)) if !id.is_empty() && !extension.is_empty() => Ok(ImageFileName {
+                id: id.to_string(),
+                extension: extension.to_string(),
+            }),
+            _ => Err(param),
+        }
+    }
+}
+
+/// The image in the format its extension asks for, for embeds that need
+/// URLs ending in .png or .jpg
+#[get("/i/<file>", rank = 1)]
+async fn view_image_file_route(
+    file: ImageFileName,
+    referrer: EmbedReferrer,
+    collections: &State<db::Collections>,
+) -> Result<Result<ImageResponder, Redirect>, Status> {
+    let target = encoding::extension_to_mimetype(&file.extension).ok_or(Status::NotFound)?;
+    let doc = find_image(&file.id, collections).await?;
+    if is_password_protected(&doc) {
+        return Ok(Err(Redirect::to(uri!(protected_image_route(file.id)))));
+    }
+    spawn_record_embed(collections, &file.id, referrer);
+    let mut image = serve_image(&doc, file.id.clone(), collections)?;
+    if image.content_type != target {
+        let extension = mime_to_extension(target);
+        let optim_level = util::get_int(&doc, "optim_level").unwrap_or(0);
+        image = image.variant(extension);
+        let stored = db::get_transcode(&collections.transcodes, &file.id, extension, optim_level)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Can't get converted image {}.{}: {}", file.id, extension, e);
+                None
+            })
+            .and_then(|transcode| util::get_stored_data(&transcode, "data", "content_type").ok());
+        image.data = match stored {
+            Some((data, _)) => data,
+            None => {
+                let source = std::mem::take(&mut image.data);
+                let key = format!("{}.{}.{}", file.id, extension, optim_level);
+                limits::run_transcode(&key, async {
+                    let data = encoding::transcode(source, &image.content_type, target)
+                        .await
+                        .map_err(|e| {
+                            info!("Can't convert {} to {}: {}", file.id, target, e);
+                            Status::NotFound
+                        })?;
+                    // storing it can fail for huge images, they're converted every time then
+                    if let Err(e) = db::set_transcode(
+                        &collections.transcodes,
+                        &file.id,
+                        extension,
+                        optim_level,
+                        &data,
+                        target,
+                    )
+                    .await
+                    {
+                        error!(
+                            "Can't store converted image {}.{}: {}",
+                            file.id, extension, e
+                        );
+                    }
+                    Ok(data)
+                })
+                .await?
+            }
+        };
+        image.content_type = target.to_string();
+    }
+    image.headers.push(Header::new(
+        "Link",
+        format!("<https://{}/i/{}>; rel=\"canonical\"", *HOST, file.id),
+    ));
+    Ok(Ok(image))
+}
+
+/// An image id without a file extension, so requests for ids with one are
+/// forwarded to the routes that handle them
+struct PlainImageId(String);
+
+impl<'a> FromParam<'a> for PlainImageId {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        if param.is_empty() || param.contains('.') {
+            return Err(param);
+        }
+        Ok(PlainImageId(param.to_string()))
+    }
+}
+
+/// The headers of GET /i/<id> without loading the image data. Other HEAD
+/// requests are answered by the GET routes.
+#[head("/i/<id>")]
+async fn head_image_route(
+    id: PlainImageId,
+    collections: &State<db::Collections>,
+) -> Result<Result<ImageResponder, Redirect>, Status> {
+    let doc = db::get_images_metadata(&collections.images, std::slice::from_ref(&id.0))
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .pop()
+        .ok_or(Status::NotFound)?;
+    if is_password_protected(&doc) {
+        return Ok(Err(Redirect::to(uri!(protected_image_route(id.0)))));
+    }
+    let size = util::get_int(&doc, "size")
+        .filter(|size| *size > 0)
+        .ok_or(Status::Gone)?;
+    let ct = match doc.get_str("content_type") {
+        Ok(ct) if !ct.is_empty() => ct.to_string(),
+        // old images without a stored type need the data to guess it, the
+        // same way GET does
+        _ => {
+            let doc = find_image(&id.0, collections).await?;
+            util::get_stored_data(&doc, "data", "content_type")
+                .map_err(|e| {
+                    error!("Can't serve image {}: {:?} data", id.0, e);
+                    Status::Gone
+                })?
+                .1
+        }
+    };
+
+    let mut image = ImageResponder::new(&doc, Vec::new(), ct);
+    image.head_size = Some(size as usize);
+    Ok(Ok(image))
+}
+
+#[get("/i/<id>", rank = 2)]
+async fn view_image_route(
+    id: String,
+    referrer: EmbedReferrer,
+    collections: &State<db::Collections>,
+) -> Result<Result<ImageResponder, Redirect>, Status> {
+    let doc = find_image(&id, collections).await?;
+    if is_password_protected(&doc) {
+        return Ok(Err(Redirect::to(uri!(protected_image_route(id)))));
+    }
+    spawn_record_embed(collections, &id, referrer);
+    serve_image(&doc, id, collections).map(Ok)
 }
 
 #[get("/i/<id>/thumb")]
 async fn view_thumbnail_route(
     id: String,
+    referrer: EmbedReferrer,
+    collections: &State<db::Collections>,
+) -> Result<Result<ImageResponder, Redirect>, Status> {
+    let doc = find_image(&id, collections).await?;
+    if is_password_protected(&doc) {
+        return Ok(Err(Redirect::to(uri!(protected_image_route(id)))));
+    }
+    spawn_record_embed(collections, &id, referrer);
+    serve_thumbnail(&doc, &id, collections).await.map(Ok)
+}
+
+/// How long entering the password of a protected image lets you view it for
+const IMAGE_SESSION_LIFETIME: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const IMAGE_SESSION_COOKIE: &str = "image_session";
+
+/// The session cookie is named after the image, so it can be sent to every
+/// route about the image (like its embeds) without sessions for different
+/// images overwriting each other
+fn image_session_cookie_name(id: &str) -> String {
+    format!("{}_{}", IMAGE_SESSION_COOKIE, id)
+}
+
+#[derive(FromForm)]
+struct PasswordForm {
+    password: String,
+}
+
+/// The page asking for the password of a protected image
+fn password_page(id: &str, status: Status, message: Option<&str>) -> (Status, RawHtml<String>) {
+    let message = message
+        .map(|message| format!("<p>{}</p>", message))
+        .unwrap_or_default();
+    (
+        status,
+        RawHtml(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+	<meta charset="utf-8" />
+	<meta name="viewport" content="width=device-width, initial-scale=1" />
+	<meta name="robots" content="noindex" />
+	<title>Password required</title>
+</head>
+<body>
+	<form method="post" action="/p/{id}">
+		<label for="password">This image is password protected</label>
+		<input type="password" id="password" name="password" autofocus required />
+		<button type="submit">View</button>
+	</form>
+	{message}
+</body>
+</html>"#
+        )),
+    )
+}
+
+/// Whether the request has a valid session cookie for the protected image
+async fn has_image_session(
+    cookies: &CookieJar<'_>,
+    id: &str,
+    collections: &db::Collections,
+) -> bool {
+    match cookies.get(&image_session_cookie_name(id)) {
+        Some(cookie) => db::check_image_session(
+            &collections.image_sessions,
+            &ImageId(id.to_string()),
+            cookie.value(),
+        )
+        .await
+        .unwrap_or(false),
+        None => false,
+    }
+}
+
+#[get("/p/<id>")]
+async fn protected_image_route(
+    id: String,
+    cookies: &CookieJar<'_>,
+    collections: &State<db::Collections>,
+) -> Result<Result<ImageResponder, (Status, RawHtml<String>)>, Status> {
+    let doc = find_image(&id, collections).await?;
+    if !is_password_protected(&doc) || has_image_session(cookies, &id, collections).await {
+        return serve_image(&doc, id, collections).map(Ok);
+    }
+    Ok(Err(password_page(&id, Status::Ok, None)))
+}
+
+#[get("/p/<id>/thumb")]
+async fn protected_thumbnail_route(
+    id: String,
+    cookies: &CookieJar<'_>,
+    collections: &State<db::Collections>,
+) -> Result<Result<ImageResponder, Redirect>, Status> {
+    let doc = find_image(&id, collections).await?;
+    if !is_password_protected(&doc) || has_image_session(cookies, &id, collections).await {
+        return serve_thumbnail(&doc, &id, collections).await.map(Ok);
+    }
+    Ok(Err(Redirect::to(uri!(protected_image_route(id)))))
+}
+
+#[post("/p/<id>", data = "<form>")]
+async fn unlock_protected_image_route(
+    id: String,
+    form: Form<PasswordForm>,
+    attempt: limits::PasswordAttempt,
+    cookies: &CookieJar<'_>,
     collections: &State<db::Collections>,
-) -> Option<ImageResponder> {
+) -> Option<Result<Redirect, (Status, RawHtml<String>)>> {
     let doc = db::get_image(&collections.images, &id).await.ok()??;
-    let data = doc.get_binary_generic("thumbnail_data").unwrap().clone();
-    let ct = doc.get_str("thumbnail_content_type").unwrap().to_string();
-    Some(ImageResponder(data, Header::new("Content-Type", ct)))
+    let password_hash = match doc.get_str("password_hash") {
+        Ok(password_hash) => password_hash.to_string(),
+        Err(_) => return Some(Ok(Redirect::to(uri!(view_image_route(id))))),
+    };
+
+    let Some(_permit) = attempt.try_start(&id) else {
+        return Some(Err(password_page(
+            &id,
+            Status::TooManyRequests,
+            Some("Too many attempts, wait a minute and try again"),
+        )));
+    };
+    let password = form.into_inner().password;
+    let is_correct = task::spawn_blocking(move || util::verify_password(&password, &password_hash))
+        .await
+        .unwrap_or(false);
+    attempt.finish(&id, is_correct);
+    if !is_correct {
+        return Some(Err(password_page(
+            &id,
+            Status::Forbidden,
+            Some("Wrong password"),
+        )));
+    }
+
+    let token = match db::create_image_session(
+        &collections.image_sessions,
+        &ImageId(id.clone()),
+        IMAGE_SESSION_LIFETIME,
+    )
+    .await
+    {
+        Ok(token) => token,
+        Err(_) => {
+            return Some(Err(password_page(
+                &id,
+                Status::InternalServerError,
+                Some("Something went wrong, try again"),
+            )))
+        }
+    };
+    cookies.add(
+        Cookie::build((image_session_cookie_name(&id), token))
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .max_age(rocket::time::Duration::seconds(
+                IMAGE_SESSION_LIFETIME.as_secs() as i64,
+            )),
+    );
+    Some(Ok(Redirect::to(uri!(protected_image_route(id)))))
+}
+
+#[get("/api/images/<id>/embeds")]
+async fn api_image_embeds(
+    id: String,
+    cookies: &CookieJar<'_>,
+    collections: &State<db::Collections>,
+) -> Result<CachedJson<ApiEmbedsResponse>, Custom<Json<ApiErrorResponse>>> {
+    // only the metadata, to not load the whole image
+    let doc = db::get_images_metadata(&collections.images, std::slice::from_ref(&id))
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?
+        .pop()
+        .ok_or_else(|| create_error(Status::NotFound, "Image not found"))?;
+    // who embeds a protected image is as private as the image itself
+    let private = is_password_protected(&doc);
+    if private && !has_image_session(cookies, &id, collections).await {
+        return Err(create_error(Status::NotFound, "Image not found"));
+    }
+    let image_id = ImageId(id);
+
+    let embeds = db::get_embeds(&collections.embeds, &image_id)
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?;
+
+    Ok(CachedJson {
+        value: ApiEmbedsResponse {
+            data: embeds
+                .iter()
+                .filter_map(|doc| {
+                    Some(ApiEmbed {
+                        domain: doc.get_str("domain").ok()?.to_string(),
+                        day: doc.get_datetime("day").ok()?.timestamp_millis() / 1000,
+                        count: doc.get_i64("count").ok()?,
+                    })
+                })
+                .collect(),
+            success: true,
+            status: 200,
+        },
+        private,
+    })
+}
+
+/// The longest a share link can be made to last, ten years
+const MAX_SHARE_LINK_EXPIRES_IN: u64 = 10 * 365 * 24 * 60 * 60;
+
+#[post("/api/images/<id>/share-links", data = "<data>", format = "json")]
+async fn api_create_share_link(
+    id: String,
+    data: Json<ApiShareLinkRequest>,
+    attempt: limits::PasswordAttempt,
+    collections: &State<db::Collections>,
+) -> Result<Json<ApiShareLinkResponse>, Custom<Json<ApiErrorResponse>>> {
+    let req = data.into_inner();
+    if req.max_views == Some(0) || req.expires_in == Some(0) {
+        return Err(create_error(
+            Status::BadRequest,
+            "max_views and expires_in must be more than 0",
+        ));
+    }
+    if req
+        .expires_in
+        .is_some_and(|expires_in| expires_in > MAX_SHARE_LINK_EXPIRES_IN)
+    {
+        return Err(create_error(
+            Status::BadRequest,
+            &format!(
+                "expires_in can't be more than {}",
+                MAX_SHARE_LINK_EXPIRES_IN
+            ),
+        ));
+    }
+    let doc = db::get_image(&collections.images, &id)
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?
+        .ok_or_else(|| create_error(Status::NotFound, "Image not found"))?;
+
+    if let Ok(password_hash) = doc.get_str("password_hash") {
+        let _permit = attempt.try_start(&id).ok_or_else(|| {
+            create_error(
+                Status::TooManyRequests,
+                "Too many password attempts, try again later",
+            )
+        })?;
+        let password = req.password.unwrap_or_default();
+        let password_hash = password_hash.to_string();
+        let is_correct =
+            task::spawn_blocking(move || util::verify_password(&password, &password_hash))
+                .await
+                .unwrap_or(false);
+        attempt.finish(&id, is_correct);
+        if !is_correct {
+            return Err(create_error(Status::Forbidden, "Wrong password"));
+        }
+    }
+
+    let expires = req.expires_in.map(|expires_in| {
+        bson::DateTime::from_millis(
+            bson::DateTime::now().timestamp_millis() + expires_in as i64 * 1000,
+        )
+    });
+    let token = db::create_share_link(
+        &collections.share_links,
+        &ImageId(id),
+        req.max_views.map(i64::from),
+        expires,
+    )
+    .await
+    .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?;
+
+    Ok(Json(ApiShareLinkResponse {
+        data: ApiShareLink {
+            url: format!("https://{}/s/{}", *HOST, token),
+            max_views: req.max_views,
+            expiration: expires.map(|expires| expires.timestamp_millis() / 1000),
+        },
+        success: true,
+        status: 200,
+    }))
+}
+
+#[get("/api/images/<id>/srcset")]
+async fn api_image_srcset(
+    id: String,
+    cookies: &CookieJar<'_>,
+    collections: &State<db::Collections>,
+) -> Result<CachedJson<ApiSrcsetResponse>, Custom<Json<ApiErrorResponse>>> {
+    // only the metadata, to not load the whole image
+    let doc = db::get_images_metadata(&collections.images, std::slice::from_ref(&id))
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?
+        .pop()
+        .ok_or_else(|| create_error(Status::NotFound, "Image not found"))?;
+    // the size and format of a protected image are as private as the image
+    if is_password_protected(&doc) && !has_image_session(cookies, &id, collections).await {
+        return Err(create_error(Status::NotFound, "Image not found"));
+    }
+
+    let width = util::get_int(&doc, "width").unwrap_or(0) as u32;
+    let height = util::get_int(&doc, "height").unwrap_or(0) as u32;
+    let (thumb_width, thumb_height) =
+        encoding::clamp_im_size(width, height, encoding::THUMBNAIL_SIZE);
+    let route = if is_password_protected(&doc) {
+        "p"
+    } else {
+        "i"
+    };
+    let image_url = format!("https://{}/{}/{}", *HOST, route, id);
+
+    // smallest first, like browsers expect
+    let variants = vec![
+        ApiSrcsetVariant {
+            url: format!("{}/thumb", image_url),
+            width: thumb_width,
+            height: thumb_height,
+            mime: doc
+                .get_str("thumbnail_content_type")
+                .unwrap_or("image/webp")
+                .to_string(),
+        },
+        ApiSrcsetVariant {
+            url: image_url.clone(),
+            width,
+            height,
+            mime: doc
+                .get_str("content_type")
+                .unwrap_or("image/webp")
+                .to_string(),
+        },
+    ];
+
+    let srcset = variants
+        .iter()
+        .map(|variant| format!("{} {}w", variant.url, variant.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let img = format!(
+        r#"<img src="{}" srcset="{}" sizes="(max-width: {}px) 100vw, {}px" width="{}" height="{}" alt="" loading="lazy">"#,
+        image_url, srcset, width, width, width, height
+    );
+    let sources = variants
+        .iter()
+        .map(|variant| {
+            format!(
+                r#"<source srcset="{}" type="{}" media="(max-width: {}px)">"#,
+                variant.url, variant.mime, variant.width
+            )
+        })
+        .collect::<String>();
+    let picture = format!("<picture>{}{}</picture>", sources, img);
+
+    Ok(CachedJson {
+        value: ApiSrcsetResponse {
+            data: ApiSrcset {
+                srcset,
+                img,
+                picture,
+                variants,
+            },
+            success: true,
+            status: 200,
+        },
+        private: is_password_protected(&doc),
+    })
+}
+
+#[get("/s/<token>")]
+async fn share_link_route(
+    token: String,
+    collections: &State<db::Collections>,
+) -> Result<ImageResponder, Status> {
+    let image_id = match db::use_share_link(&collections.share_links, &token).await {
+        Ok(db::ShareLinkUse::Valid(image_id)) => image_id,
+        Ok(db::ShareLinkUse::Gone) => return Err(Status::Gone),
+        Ok(db::ShareLinkUse::NotFound) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let doc = db::get_image(&collections.images, &image_id.0)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::Gone)?;
+    // every request has to reach us so the views are counted
+    let mut image = serve_image(&doc, image_id.0, collections)?;
+    image.cache_control = "no-store".to_string();
+    Ok(image)
+}
+
+/// Run an image through the upload pipeline without storing anything and
+/// report what happened at each step
+#[post("/api/debug/process", data = "<data>")]
+async fn api_debug_process(
+    _permit: limits::UploadPermit,
+    data: Data<'_>,
+) -> Result<Json<ApiDebugResponse>, Custom<Json<ApiErrorResponse>>> {
+    if !*ENABLE_DEBUG_ENDPOINT {
+        return Err(create_error(Status::NotFound, "Not found"));
+    }
+    let mut timer = StageTimer::new();
+
+    let image_bytes = limits::read_upload_body(data, 20.megabytes())
+        .await
+        .map_err(|(status, e)| create_error(status, &e))?;
+    timer.end("read");
+
+    let detected_mime = infer::get(&image_bytes).map(|kind| kind.mime_type().to_string());
+    let decoded_format = image::guess_format(&image_bytes)
+        .ok()
+        .map(|format| format!("{:?}", format));
+    let decoded_image = image::load_from_memory(&image_bytes).map_err(|e| {
+        create_error(
+            Status::BadRequest,
+            &format!("Failed to decode image: {}", e),
+        )
+    })?;
+    timer.end("decode");
+
+    let quality_image = decoded_image.clone();
+    let quality = task::spawn_blocking(move || quality::assess(&quality_image))
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?;
+    timer.end("assess_quality");
+
+    let planned_variants = [
+        ("upload", None, false),
+        ("upload_thumbnail", Some(encoding::THUMBNAIL_SIZE), false),
+        ("optimized", Some(1024), true),
+        ("optimized_thumbnail", Some(encoding::THUMBNAIL_SIZE), true),
+    ];
+    let mut variants = Vec::new();
+    for (name, max_size, optimize_png) in planned_variants {
+        let encoded = encoding::from_image(
+            decoded_image.clone(),
+            encoding::FromImageOptions {
+                max_size,
+                optimize_png,
+                ..encoding::FromImageOptions::default()
+            },
+        )
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e))?;
+        timer.end(name);
+        variants.push(ApiDebugVariant {
+            name,
+            max_size,
+            optimize_png,
+            width: encoded.size.0,
+            height: encoded.size.1,
+            mime: encoded.content_type,
+            size: encoded.data.len(),
+        });
+    }
+
+    Ok(Json(ApiDebugResponse {
+        data: ApiDebugTrace {
+            received_bytes: image_bytes.len(),
+            detected_mime,
+            decoded_format,
+            color_type: format!("{:?}", decoded_image.color()),
+            width: decoded_image.width(),
+            height: decoded_image.height(),
+            quality,
+            variants,
+            stages: timer.stages,
+        },
+        success: true,
+        status: 200,
+    }))
+}
+
+/// The public metadata of an image from a document returned by
+/// `db::get_images_metadata`
+fn image_metadata(doc: &mongodb::bson::Document) -> ApiImageMetadata {
+    let id = doc.get_str("_id").unwrap_or_default();
+    let password_protected = is_password_protected(doc);
+    let route = if password_protected { "p" } else { "i" };
+    let image_url = format!("https://{}/{}/{}", *HOST, route, id);
+    ApiImageMetadata {
+        thumb_url: format!("{}/thumb", image_url),
+        url: image_url,
+        width: util::get_int(doc, "width").unwrap_or(0),
+        height: util::get_int(doc, "height").unwrap_or(0),
+        mime: doc.get_str("content_type").unwrap_or_default().to_string(),
+        size: util::get_int(doc, "size").unwrap_or(0),
+        time: doc
+            .get_datetime("date")
+            .map(|date| date.timestamp_millis() / 1000)
+            .unwrap_or(0),
+        quality: doc
+            .get_document("quality")
+            .and_then(|quality| quality.get_f64("score"))
+            .ok(),
+        license: doc
+            .get_str("license")
+            .ok()
+            .map(|license| license.to_string()),
+        noai: doc.get_bool("noai").unwrap_or(false),
+        password_protected,
+    }
+}
+
+/// Get the metadata of many images at once, so galleries don't need a
+/// request per image
+#[post("/api/images/metadata/batch", data = "<data>", format = "json")]
+async fn api_images_metadata_batch(
+    data: Json<ApiMetadataBatchRequest>,
+    cookies: &CookieJar<'_>,
+    collections: &State<db::Collections>,
+) -> Result<Json<ApiMetadataBatchResponse>, Custom<Json<ApiErrorResponse>>> {
+    let ids = data.into_inner().ids;
+    if ids.is_empty() || ids.len() > MAX_METADATA_BATCH_SIZE {
+        return Err(create_error(
+            Status::BadRequest,
+            &format!(
+                "ids must have between 1 and {} items",
+                MAX_METADATA_BATCH_SIZE
+            ),
+        ));
+    }
+
+    let docs = db::get_images_metadata(&collections.images, &ids)
+        .await
+        .map_err(|e| create_error(Status::InternalServerError, &e.to_string()))?;
+    let docs_by_id: std::collections::HashMap<&str, &mongodb::bson::Document> = docs
+        .iter()
+        .filter_map(|doc| Some((doc.get_str("_id").ok()?, doc)))
+        .collect();
+
+    let mut items = Vec::with_capacity(ids.len());
+    for id in &ids {
+        let item = match docs_by_id.get(id.as_str()) {
+            // like viewing it, protected images need to be unlocked first
+            Some(doc)
+                if is_password_protected(doc)
+                    && !has_image_session(cookies, id, collections).await =>
+            {
+                ApiMetadataBatchItem {
+                    id: id.clone(),
+                    metadata: None,
+                    error: Some("Image is password protected".to_string()),
+                    locked: true,
+                }
+            }
+            Some(doc) => ApiMetadataBatchItem {
+                id: id.clone(),
+                metadata: Some(image_metadata(doc)),
+                error: None,
+                locked: false,
+            },
+            None => ApiMetadataBatchItem {
+                id: id.clone(),
+                metadata: None,
+                error: Some("Image not found".to_string()),
+                locked: false,
+            },
+        };
+        items.push(item);
+    }
+
+    Ok(Json(ApiMetadataBatchResponse {
+        data: items,
+        success: true,
+        status: 200,
+    }))
+}
+
+/// Store a tiny generated image, read it back and check it survived
+async fn run_canary(
+    images_collection: &mongodb::Collection<mongodb::bson::Document>,
+    image_id: &ImageId,
+    timer: &mut StageTimer,
+) -> Result<(), (&'static str, String)> {
+    let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(8, 8, |x, y| {
+        image::Rgb([x as u8 * 32, y as u8 * 32, 128])
+    }));
+    let encoded = encoding::from_image(image, encoding::FromImageOptions::default())
+        .await
+        .map_err(|e| ("encode", e))?;
+    timer.end("encode");
+
+    db::insert_image(
+        images_collection,
+        &db::NewImage {
+            id: image_id,
+            data: &encoded.data,
+            content_type: &encoded.content_type,
+            thumbnail_data: &encoded.data,
+            thumbnail_content_type: &encoded.content_type,
+            size: encoded.size,
+            // so background optimization leaves it alone
+            optim_level: 1,
+            settings: &db::ImageSettings::default(),
+            quality: None,
+        },
+    )
+    .await
+    .map_err(|e| ("store", e.to_string()))?;
+    timer.end("store");
+
+    let doc = db::get_image(images_collection, &image_id.0)
+        .await
+        .map_err(|e| ("retrieve", e.to_string()))?
+        .ok_or(("retrieve", "Image wasn't stored".to_string()))?;
+    let (data, _) = util::get_stored_data(&doc, "data", "content_type")
+        .map_err(|e| ("retrieve", format!("{:?} data", e)))?;
+    if data != encoded.data {
+        return Err(("retrieve", "Stored data doesn't match".to_string()));
+    }
+    timer.end("retrieve");
+    Ok(())
+}
+
+/// Run a tiny upload through encoding, storing, retrieving and deleting, so
+/// monitors notice when the pipeline is broken and not just when the process
+/// is down
+#[get("/health/canary")]
+async fn health_canary(
+    _permit: limits::UploadPermit,
+    collections: &State<db::Collections>,
+) -> Result<Custom<Json<ApiCanaryResponse>>, Custom<Json<ApiErrorResponse>>> {
+    if !*ENABLE_CANARY_ENDPOINT {
+        return Err(create_error(Status::NotFound, "Not found"));
+    }
+    let mut timer = StageTimer::new();
+    // real ids only use util::ID_ALPHABET (at any length, since they grow
+    // when they collide), which has no '.', so this can't clash with one
+    let image_id = ImageId(format!("canary.{}", util::generate_random_id(16)));
+
+    let mut result = run_canary(&collections.images, &image_id, &mut timer).await;
+    // clean up even if a stage failed
+    let deleted = db::delete_image(&collections.images, &image_id).await;
+    if result.is_ok() {
+        result = match deleted {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(("delete", "Image was already gone".to_string())),
+            Err(e) => Err(("delete", e.to_string())),
+        };
+    }
+    timer.end("delete");
+
+    let (failed_stage, error) = match result {
+        Ok(()) => (None, None),
+        Err((stage, e)) => {
+            error!("Canary failed at {}: {}", stage, e);
+            (Some(stage), Some(e))
+        }
+    };
+    let status = if failed_stage.is_none() {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    Ok(Custom(
+        status,
+        Json(ApiCanaryResponse {
+            data: ApiCanaryResult {
+                healthy: failed_stage.is_none(),
+                failed_stage,
+                error,
+                stages: timer.stages,
+            },
+            success: failed_stage.is_none(),
+            status: status.code,
+        }),
+    ))
+}
+
+/// How busy the database connection pool is, to tell whether requests are
+/// waiting on connections, and how many uploads were dropped
+#[get("/health/db")]
+fn health_db() -> Json<ApiDatabasePoolResponse> {
+    Json(ApiDatabasePoolResponse {
+        data: db_pool::POOL_STATS.snapshot(),
+        uploads: limits::upload_snapshot(),
+        success: true,
+        status: 200,
+    })
+}
+
+#[get("/robots.txt")]
+fn robots_txt() -> (ContentType, &'static str) {
+    (ContentType::Plain, ROBOTS_TXT.as_str())
 }
 
 #[get("/image/<id>")]
@@ -457,32 +1915,151 @@ fn redirect_image_route(id: String) -> Redirect {
     Redirect::to(uri!(view_image_route(id)))
 }
 
+#[get("/<_..>")]
+fn https_redirect(origin: &rocket::http::uri::Origin<'_>) -> Redirect {
+    Redirect::permanent(format!("https://{}{}", *HOST, origin))
+}
+
+/// Start a plain HTTP server that only redirects to the HTTPS one
+async fn launch_https_redirect(port: u16) -> Result<(), rocket::Error> {
+    let address = rocket::Config::figment()
+        .extract_inner("address")
+        .unwrap_or(rocket::Config::default().address);
+    let config = rocket::Config {
+        address,
+        port,
+        ..rocket::Config::default()
+    };
+    rocket::custom(config)
+        .mount("/", routes![https_redirect])
+        .launch()
+        .await?;
+    Ok(())
+}
+
 #[launch]
 async fn rocket() -> _ {
     dotenv().ok();
     env_logger::init();
-    let images_collection = db::connect().await.unwrap();
+    if let Err(errors) = config::check() {
+        for error in errors {
+            error!("Invalid setting {}", error);
+        }
+        std::process::exit(1);
+    }
+    if std::env::args().any(|arg| arg == "--check-config") {
+        println!("Settings are valid");
+        std::process::exit(0);
+    }
+    let collections = db::connect().await.unwrap();
     println!("Connected to database");
 
-    let collections = db::Collections {
-        images: images_collection.clone(),
-    };
+    let images_collection = collections.images.clone();
     tokio::spawn(async move {
         optimize_images_from_database(&images_collection)
             .await
             .expect("Failed optimizing images");
     });
+    if let Some(port) = *HTTPS_REDIRECT_PORT {
+        tokio::spawn(async move {
+            if let Err(e) = launch_https_redirect(port).await {
+                error!("HTTPS redirect server failed: {}", e);
+            }
+        });
+    }
 
-    rocket::build().manage(collections).mount(
-        "/",
-        routes![
-            index,
-            api_upload_json,
-            api_upload_form,
-            api_upload_fallback,
-            view_image_route,
-            redirect_image_route,
-            view_thumbnail_route
-        ],
-    )
+    rocket::build()
+        .attach(chaos::Chaos)
+        .manage(collections)
+        .register(
+            "/",
+            catchers![
+                not_found,
+                gone,
+                too_many_requests,
+                service_unavailable,
+                unavailable_for_legal_reasons
+            ],
+        )
+        .mount(
+            "/",
+            routes![
+                index,
+                robots_txt,
+                api_upload_json,
+                api_upload_form,
+                api_upload_fallback,
+                view_image_route,
+                view_image_file_route,
+                head_image_route,
+                redirect_image_route,
+                view_thumbnail_route,
+                protected_image_route,
+                protected_thumbnail_route,
+                unlock_protected_image_route,
+                api_create_share_link,
+                share_link_route,
+                api_image_embeds,
+                api_image_srcset,
+                api_images_metadata_batch,
+                api_debug_process,
+                health_canary,
+                health_db
+            ],
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{gradient, png_bytes};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::Value;
+
+    // the upload routes store images in MongoDB, so the handler stack is
+    // tested through the debug endpoint, which runs the same pipeline
+    // without storing anything
+    #[rocket::async_test]
+    async fn debug_process_runs_upload_pipeline() {
+        std::env::set_var("ENABLE_DEBUG_ENDPOINT", "true");
+        let client = Client::untracked(rocket::build().mount("/", routes![api_debug_process]))
+            .await
+            .unwrap();
+        let fixture = gradient(300, 200);
+        let response = client
+            .post("/api/debug/process")
+            .body(png_bytes(&fixture))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        let trace = &body["data"];
+        assert_eq!(trace["detected_mime"], "image/png");
+        assert_eq!(
+            (trace["width"].as_u64(), trace["height"].as_u64()),
+            (Some(300), Some(200))
+        );
+
+        let thumbnail = trace["variants"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|variant| variant["name"] == "upload_thumbnail")
+            .unwrap();
+        let expected = encoding::from_image(
+            fixture,
+            encoding::FromImageOptions {
+                max_size: Some(encoding::THUMBNAIL_SIZE),
+                ..encoding::FromImageOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(thumbnail["mime"], "image/webp");
+        assert_eq!(
+            (thumbnail["width"].as_u64(), thumbnail["height"].as_u64()),
+            (Some(128), Some(85))
+        );
+        assert_eq!(thumbnail["size"].as_u64(), Some(expected.data.len() as u64));
+    }
 }
@@ -0,0 +1,95 @@
+//! Placeholder images that are served instead of bare errors on image routes,
+//! so pages embedding a missing image don't end up with a broken layout.
+
+use rocket::http::{Header, Method, Status};
+use rocket::response::status::Custom;
+use rocket::Request;
+
+lazy_static! {
+    /// Served with 404 Not Found for images that don't exist
+    static ref NOT_FOUND_PLACEHOLDER: Option<Placeholder> = load("NOT_FOUND_PLACEHOLDER_PATH");
+    /// Served with 410 Gone for images that were deleted, expired or are
+    /// corrupt
+    static ref GONE_PLACEHOLDER: Option<Placeholder> = load("GONE_PLACEHOLDER_PATH");
+}
+
+/// The paths that serve image data, as opposed to the JSON API
+const IMAGE_PATH_PREFIXES: [&str; 4] = ["/i/", "/p/", "/s/", "/image/"];
+
+pub struct Placeholder {
+    data: Vec<u8>,
+    content_type: String,
+}
+
+/// Read the placeholder image at the path in the environment variable `var`
+fn load(var: &str) -> Option<Placeholder> {
+    let path = std::env::var(var).ok()?;
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to read {} {}: {}", var, path, e);
+            return None;
+        }
+    };
+    let content_type = match infer::get(&data) {
+        Some(kind) if kind.matcher_type() == infer::MatcherType::Image => kind.mime_type(),
+        _ => {
+            error!("{} {} isn't an image", var, path);
+            return None;
+        }
+    };
+    Some(Placeholder {
+        data,
+        content_type: content_type.to_string(),
+    })
+}
+
+/// Whether a request is for image data that a placeholder can stand in for
+fn is_image_request(method: Method, path: &str) -> bool {
+    matches!(method, Method::Get | Method::Head)
+        && IMAGE_PATH_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+}
+
+#[derive(Responder)]
+pub struct PlaceholderResponder(Vec<u8>, Header<'static>, Header<'static>);
+
+/// The placeholder for `status` if one is configured and the request is for
+/// an image
+pub fn for_request(req: &Request<'_>, status: Status) -> Option<Custom<PlaceholderResponder>> {
+    if !is_image_request(req.method(), req.uri().path().as_str()) {
+        return None;
+    }
+    let placeholder = match status.code {
+        404 => NOT_FOUND_PLACEHOLDER.as_ref(),
+        410 => GONE_PLACEHOLDER.as_ref(),
+        _ => None,
+    }?;
+    Some(Custom(
+        status,
+        PlaceholderResponder(
+            placeholder.data.clone(),
+            Header::new("Content-Type", placeholder.content_type.clone()),
+            // so the real image shows up if it comes back
+            Header::new("Cache-Control", "no-store"),
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn is_image_request_checks_path() {
+        assert!(is_image_request(Method::Get, "/i/abc123"));
+        assert!(is_image_request(Method::Get, "/i/abc123/thumb"));
+        assert!(is_image_request(Method::Head, "/s/token"));
+        assert!(!is_image_request(Method::Get, "/api/images/abc123/embeds"));
+        assert!(!is_image_request(Method::Get, "/robots.txt"));
+    }
+    #[test]
+    fn is_image_request_checks_method() {
+        assert!(!is_image_request(Method::Post, "/p/abc123"));
+    }
+}
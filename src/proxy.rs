@@ -0,0 +1,186 @@
+//! Working out which IP a request really came from. The X-Real-IP and
+//! X-Forwarded-For headers are only believed when the connection comes from
+//! one of TRUSTED_PROXIES, since anyone can send them.
+
+use rocket::Request;
+use std::net::IpAddr;
+
+lazy_static! {
+    /// Reverse proxies allowed to tell us the client's IP, as a comma
+    /// separated list of IPs or CIDR ranges like "127.0.0.1,10.0.0.0/8"
+    static ref TRUSTED_PROXIES: Vec<IpRange> = std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| {
+            let range = IpRange::parse(item);
+            if range.is_none() {
+                error!("Ignoring invalid TRUSTED_PROXIES entry {}", item);
+            }
+            range
+        })
+        .collect();
+}
+
+/// A single IP or a CIDR range of them
+#[derive(Debug, PartialEq, Eq)]
+struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    fn parse(range: &str) -> Option<Self> {
+        let (network, prefix_len) = match range.split_once('/') {
+            Some((network, prefix_len)) => (network.parse().ok()?, prefix_len.parse().ok()?),
+            None => {
+                let network: IpAddr = range.parse().ok()?;
+                (network, if network.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        (prefix_len <= max_len).then_some(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // so ::ffff:10.0.0.1 matches 10.0.0.0/8
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether an entry of TRUSTED_PROXIES is a valid IP or CIDR range
+pub fn is_valid_trusted_proxy(entry: &str) -> bool {
+    IpRange::parse(entry).is_some()
+}
+
+/// The client's IP given the IP of the connection and the proxy headers.
+/// X-Real-IP wins over X-Forwarded-For, and in X-Forwarded-For the rightmost
+/// IP that isn't a trusted proxy is the client, since everything left of it
+/// could have been made up by the client.
+fn resolve_client_ip(
+    peer: IpAddr,
+    real_ip: Option<&str>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpRange],
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+    if let Some(ip) = real_ip.and_then(|ip| ip.trim().parse().ok()) {
+        return ip;
+    }
+    let forwarded: Vec<IpAddr> = forwarded_for
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(**ip))
+        .or(forwarded.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+/// The IP of the client that made the request, or None if Rocket doesn't
+/// know the connection's address (like in local tests)
+pub fn client_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let peer = req.remote()?.ip();
+    Some(resolve_client_ip(
+        peer,
+        req.headers().get_one("X-Real-IP"),
+        req.headers().get_one("X-Forwarded-For"),
+        &TRUSTED_PROXIES,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+    #[test]
+    fn ip_range_parse_works() {
+        assert_eq!(
+            IpRange::parse("10.0.0.0/8"),
+            Some(IpRange {
+                network: ip("10.0.0.0"),
+                prefix_len: 8
+            })
+        );
+        assert_eq!(IpRange::parse("::1").unwrap().prefix_len, 128);
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("proxy"), None);
+    }
+    #[test]
+    fn ip_range_contains_works() {
+        let range = IpRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("11.0.0.1")));
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(IpRange::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(!IpRange::parse("127.0.0.1")
+            .unwrap()
+            .contains(ip("127.0.0.2")));
+    }
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let trusted = [IpRange::parse("10.0.0.1").unwrap()];
+        assert_eq!(
+            resolve_client_ip(ip("1.2.3.4"), Some("5.6.7.8"), Some("5.6.7.8"), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("1.2.3.4"), Some("5.6.7.8"), None, &[]),
+            ip("1.2.3.4")
+        );
+    }
+    #[test]
+    fn trusted_peer_headers_are_used() {
+        let trusted = [IpRange::parse("10.0.0.0/8").unwrap()];
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), Some("5.6.7.8"), None, &trusted),
+            ip("5.6.7.8")
+        );
+        // the client made up 6.6.6.6, the proxies appended the rest
+        assert_eq!(
+            resolve_client_ip(
+                ip("10.0.0.1"),
+                None,
+                Some("6.6.6.6, 5.6.7.8, 10.0.0.2"),
+                &trusted
+            ),
+            ip("5.6.7.8")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), None, None, &trusted),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Cheap no-reference image quality heuristics, good enough to tell blurry or
+//! badly exposed photos apart from decent ones.
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage};
+use serde::Serialize;
+
+/// Images are shrunk to this size before being assessed so it stays fast and
+/// the scores don't depend much on resolution
+const ASSESS_SIZE: u32 = 512;
+
+/// The Laplacian variance at which an image is considered half sharp
+const SHARPNESS_MIDPOINT: f64 = 100.0;
+
+/// All the scores are between 0 (bad) and 1 (good)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QualityScore {
+    /// How much fine detail there is, low for blurry images
+    pub sharpness: f64,
+    /// How close to well exposed the image is, low for very dark, very bright
+    /// or clipped images
+    pub exposure: f64,
+    /// The overall score
+    pub score: f64,
+}
+
+/// Assess the quality of an image, this is cpu heavy so it should be run in
+/// `spawn_blocking`
+pub fn assess(im: &DynamicImage) -> QualityScore {
+    let (width, height) = im.dimensions();
+    let gray = if width > ASSESS_SIZE || height > ASSESS_SIZE {
+        im.resize(ASSESS_SIZE, ASSESS_SIZE, FilterType::Triangle)
+            .to_luma8()
+    } else {
+        im.to_luma8()
+    };
+
+    let sharpness = sharpness(&gray);
+    let exposure = exposure(&gray);
+    QualityScore {
+        sharpness,
+        exposure,
+        score: sharpness * 0.6 + exposure * 0.4,
+    }
+}
+
+/// Variance of the Laplacian, mapped to 0..1
+fn sharpness(gray: &GrayImage) -> f64 {
+    let (width, height) = gray.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let px = |x: u32, y: u32| gray.get_pixel(x, y).0[0] as f64;
+    let mut sum = 0.0;
+    let mut sum_squares = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian =
+                px(x - 1, y) + px(x + 1, y) + px(x, y - 1) + px(x, y + 1) - 4.0 * px(x, y);
+            sum += laplacian;
+            sum_squares += laplacian * laplacian;
+        }
+    }
+    let count = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / count;
+    let variance = sum_squares / count - mean * mean;
+
+    variance / (variance + SHARPNESS_MIDPOINT)
+}
+
+/// How close the mean brightness is to the middle, penalized by how many
+/// pixels are clipped to black or white
+fn exposure(gray: &GrayImage) -> f64 {
+    let pixel_count = gray.pixels().len() as f64;
+    if pixel_count == 0.0 {
+        return 0.0;
+    }
+
+    let mut sum = 0.0;
+    let mut clipped = 0.0;
+    for pixel in gray.pixels() {
+        let value = pixel.0[0];
+        sum += value as f64;
+        if value <= 4 || value >= 251 {
+            clipped += 1.0;
+        }
+    }
+    let mean = sum / pixel_count;
+
+    (1.0 - (mean - 127.5).abs() / 127.5) * (1.0 - clipped / pixel_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::checkerboard;
+    use image::Luma;
+
+    #[test]
+    fn flat_image_is_not_sharp() {
+        let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([128])));
+        let quality = assess(&flat);
+        assert!(quality.sharpness < 0.01);
+        assert!(quality.exposure > 0.99);
+    }
+    #[test]
+    fn detailed_image_is_sharp() {
+        assert!(assess(&checkerboard(64, 64, 1)).sharpness > 0.9);
+    }
+    #[test]
+    fn black_image_is_badly_exposed() {
+        let black = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([0])));
+        assert!(assess(&black).exposure < 0.01);
+    }
+    #[test]
+    fn tiny_image_does_not_panic() {
+        let tiny = DynamicImage::ImageLuma8(GrayImage::from_pixel(1, 1, Luma([128])));
+        assert_eq!(assess(&tiny).sharpness, 0.0);
+    }
+}
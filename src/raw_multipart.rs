@@ -0,0 +1,345 @@
+//! Parsing for multipart upload forms as they arrive, and lenient parsing
+//! for multipart bodies that didn't arrive with a proper multipart/form-data
+//! Content-Type, for odd clients hitting the upload fallback.
+
+use crate::limits::UploadReadError;
+use futures::{stream, Stream};
+use multer::bytes::Bytes;
+use multer::{Field, Multipart};
+use rocket::http::Status;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// The longest boundary RFC 2046 allows
+const MAX_BOUNDARY_LENGTH: usize = 70;
+/// The biggest file an upload form can have
+const MAX_FORM_FILE_BYTES: usize = 8 * 1024 * 1024;
+/// The biggest text field an upload form can have, including an image sent
+/// as text
+const MAX_FORM_TEXT_BYTES: usize = 1024 * 1024;
+/// The fields an upload form can have, others are skipped
+const FORM_FIELDS: [&str; 5] = ["image", "noai", "password", "cache", "license"];
+
+/// A file found in a multipart body
+pub struct MultipartFile {
+    pub data: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+/// A file from an upload form that was written to disk while it arrived,
+/// deleted when this is dropped
+pub struct SpooledFile {
+    pub path: PathBuf,
+    pub content_type: Option<String>,
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to delete spooled upload {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// The fields of a multipart/form-data upload, only the first value of each
+/// is kept
+#[derive(Default)]
+pub struct UploadForm {
+    /// The `image` field if it was sent as a file
+    pub image_file: Option<SpooledFile>,
+    /// The text fields, including `image` if it was sent as text
+    pub texts: HashMap<String, String>,
+}
+
+/// The status and message to answer with when a form can't be parsed, keeping
+/// the status of errors from reading the body (like 408 Request Timeout)
+fn form_error(e: multer::Error) -> (Status, String) {
+    if let multer::Error::StreamReadFailed(source) = &e {
+        if let Some(UploadReadError(status, message)) = source.downcast_ref() {
+            return (*status, message.clone());
+        }
+    }
+    (Status::BadRequest, format!("Form parse error: {}", e))
+}
+
+/// Write a file field to a new file in `spool_dir`. Returns None for the
+/// empty file field browsers send when no file was picked.
+async fn spool_file(
+    mut field: Field<'_>,
+    spool_dir: &Path,
+) -> Result<Option<SpooledFile>, (Status, String)> {
+    let internal_error = |e: std::io::Error| {
+        error!("Failed to spool upload: {}", e);
+        (
+            Status::InternalServerError,
+            "Could not store uploaded file".to_string(),
+        )
+    };
+    let spooled = SpooledFile {
+        path: spool_dir.join(format!("upload-{:016x}", rand::random::<u64>())),
+        content_type: field.content_type().map(|ct| ct.to_string()),
+    };
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&spooled.path)
+        .await
+        .map_err(internal_error)?;
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.map_err(form_error)? {
+        size += chunk.len();
+        if size > MAX_FORM_FILE_BYTES {
+            return Err((Status::PayloadTooLarge, "Upload is too large".to_string()));
+        }
+        file.write_all(&chunk).await.map_err(internal_error)?;
+    }
+    file.flush().await.map_err(internal_error)?;
+    if size == 0 && field.file_name() == Some("") {
+        return Ok(None);
+    }
+    Ok(Some(spooled))
+}
+
+/// Read a text field, up to MAX_FORM_TEXT_BYTES
+async fn read_text(mut field: Field<'_>) -> Result<String, (Status, String)> {
+    let mut text = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(form_error)? {
+        if text.len() + chunk.len() > MAX_FORM_TEXT_BYTES {
+            return Err((
+                Status::PayloadTooLarge,
+                "Form field is too large".to_string(),
+            ));
+        }
+        text.extend_from_slice(&chunk);
+    }
+    String::from_utf8(text).map_err(|_| (Status::BadRequest, "Form isn't valid UTF-8".to_string()))
+}
+
+/// Parse a multipart/form-data upload from `body` as it arrives, writing the
+/// image file to `spool_dir` instead of keeping it in memory
+pub async fn parse_upload_form<'r, S>(
+    body: S,
+    boundary: &str,
+    spool_dir: &Path,
+) -> Result<UploadForm, (Status, String)>
+where
+    S: Stream<Item = Result<Bytes, UploadReadError>> + Send + 'r,
+{
+    let mut multipart = Multipart::new(body, boundary);
+    let mut form = UploadForm::default();
+    while let Some(field) = multipart.next_field().await.map_err(form_error)? {
+        let name = match field.name().filter(|name| FORM_FIELDS.contains(name)) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        if field.file_name().is_some() {
+            if name != "image" {
+                continue;
+            }
+            let file = spool_file(field, spool_dir).await?;
+            if form.image_file.is_none() {
+                form.image_file = file;
+            }
+        } else {
+            let text = read_text(field).await?;
+            form.texts.entry(name).or_insert(text);
+        }
+    }
+    Ok(form)
+}
+
+/// Guess the boundary of a multipart body from its first line, which should be
+/// `--` followed by the boundary
+pub fn sniff_boundary(body: &[u8]) -> Option<String> {
+    let end = body.iter().position(|byte| *byte == b'\n')?;
+    let line = std::str::from_utf8(&body[..end]).ok()?.trim_end();
+    let boundary = line.strip_prefix("--")?;
+    if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LENGTH {
+        return None;
+    }
+    Some(boundary.to_string())
+}
+
+/// Find the first part with a filename in a multipart body. Returns None if
+/// there isn't one or the body isn't valid multipart.
+pub async fn first_file(body: Vec<u8>, boundary: &str) -> Option<MultipartFile> {
+    let body = stream::once(async move { Ok::<_, Infallible>(Bytes::from(body)) });
+    let mut multipart = Multipart::new(body, boundary);
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => return None,
+            Err(e) => {
+                info!("Failed to parse raw multipart body: {}", e);
+                return None;
+            }
+        };
+        if field.file_name().is_none() {
+            continue;
+        }
+        let content_type = field.content_type().map(|ct| ct.to_string());
+        return match field.bytes().await {
+            Ok(data) => Some(MultipartFile {
+                data: data.to_vec(),
+                content_type,
+            }),
+            Err(e) => {
+                info!("Failed to read raw multipart file: {}", e);
+                None
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(boundary: &str, parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (headers, data) in parts {
+            body.extend_from_slice(format!("--{}\r\n{}\r\n\r\n", boundary, headers).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        body
+    }
+
+    #[test]
+    fn sniff_boundary_reads_first_line() {
+        assert_eq!(
+            sniff_boundary(b"--abc123\r\nContent-Disposition: form-data\r\n"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(sniff_boundary(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(sniff_boundary(b"--\r\n"), None);
+        assert_eq!(sniff_boundary(b"--no newline"), None);
+    }
+    #[rocket::async_test]
+    async fn first_file_keeps_binary_data() {
+        // invalid utf-8, and bytes that look like line endings
+        let data: &[u8] = &[
+            0x89, b'P', b'N', b'G', b'\r', b'\n', 0xff, 0x00, 0xfe, b'\n',
+        ];
+        let body = body(
+            "x",
+            &[(
+                "Content-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\nContent-Type: image/png",
+                data,
+            )],
+        );
+        let file = first_file(body, "x").await.unwrap();
+        assert_eq!(file.data, data);
+        assert_eq!(file.content_type.as_deref(), Some("image/png"));
+    }
+    #[rocket::async_test]
+    async fn first_file_skips_text_parts() {
+        let body = body(
+            "----WebKitFormBoundary7MA4YWxkTrZu0gW",
+            &[
+                ("Content-Disposition: form-data; name=\"noai\"", b"true"),
+                (
+                    "Content-Disposition: form-data; name=\"image\"; filename=\"a.gif\"",
+                    b"GIF89a",
+                ),
+            ],
+        );
+        let file = first_file(body, "----WebKitFormBoundary7MA4YWxkTrZu0gW")
+            .await
+            .unwrap();
+        assert_eq!(file.data, b"GIF89a");
+        assert_eq!(file.content_type, None);
+    }
+    fn chunks(
+        body: Vec<u8>,
+        error: Option<UploadReadError>,
+    ) -> impl Stream<Item = Result<Bytes, UploadReadError>> + Send {
+        // split so fields span several chunks
+        let mut items: Vec<_> = body
+            .chunks(7)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        items.extend(error.map(Err));
+        stream::iter(items)
+    }
+    #[rocket::async_test]
+    async fn parse_upload_form_spools_file() {
+        let body = body(
+            "x",
+            &[
+                ("Content-Disposition: form-data; name=\"noai\"", b"true"),
+                ("Content-Disposition: form-data; name=\"other\"", b"skipped"),
+                (
+                    "Content-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\nContent-Type: image/png",
+                    b"\x89PNG data",
+                ),
+            ],
+        );
+        let dir = std::env::temp_dir();
+        let form = parse_upload_form(chunks(body, None), "x", &dir)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(form.texts.get("noai").map(String::as_str), Some("true"));
+        assert!(!form.texts.contains_key("other"));
+        let file = form.image_file.unwrap();
+        assert_eq!(std::fs::read(&file.path).unwrap(), b"\x89PNG data");
+        assert_eq!(file.content_type.as_deref(), Some("image/png"));
+        let path = file.path.clone();
+        drop(file);
+        assert!(!path.exists());
+    }
+    #[rocket::async_test]
+    async fn parse_upload_form_skips_empty_file_input() {
+        let body = body(
+            "x",
+            &[
+                (
+                    "Content-Disposition: form-data; name=\"image\"; filename=\"\"",
+                    b"",
+                ),
+                (
+                    "Content-Disposition: form-data; name=\"image\"",
+                    b"data:image/png;base64,",
+                ),
+            ],
+        );
+        let form = parse_upload_form(chunks(body, None), "x", &std::env::temp_dir())
+            .await
+            .ok()
+            .unwrap();
+        assert!(form.image_file.is_none());
+        assert_eq!(
+            form.texts.get("image").map(String::as_str),
+            Some("data:image/png;base64,")
+        );
+    }
+    #[rocket::async_test]
+    async fn parse_upload_form_keeps_read_error_status() {
+        let mut body = body(
+            "x",
+            &[(
+                "Content-Disposition: form-data; name=\"image\"; filename=\"a.png\"",
+                b"data",
+            )],
+        );
+        // cut off in the middle of the file
+        body.truncate(body.len() - 10);
+        let error = UploadReadError(Status::RequestTimeout, "Upload was sent too slowly".into());
+        let result = parse_upload_form(chunks(body, Some(error)), "x", &std::env::temp_dir()).await;
+        let (status, message) = result.err().unwrap();
+        assert_eq!(status, Status::RequestTimeout);
+        assert_eq!(message, "Upload was sent too slowly");
+    }
+    #[rocket::async_test]
+    async fn first_file_rejects_garbage() {
+        assert!(first_file(b"--x\r\nnot multipart".to_vec(), "x")
+            .await
+            .is_none());
+        let no_files = body("x", &[("Content-Disposition: form-data; name=\"a\"", b"1")]);
+        assert!(first_file(no_files, "x").await.is_none());
+    }
+}
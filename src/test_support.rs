@@ -0,0 +1,47 @@
+//! Deterministic test images and helpers for asserting on pipeline output.
+//!
+//! The images are generated in code rather than checked in as binary
+//! fixtures, and are RGB because the webp encoder rejects grayscale.
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+/// A smooth RGB gradient, the kind of image lossy formats handle well
+pub fn gradient(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        Rgb([
+            (x * 255 / width.max(1)) as u8,
+            (y * 255 / height.max(1)) as u8,
+            ((x + y) * 255 / (width + height).max(1)) as u8,
+        ])
+    }))
+}
+
+/// A gray checkerboard with `cell` sized squares, full of hard edges like
+/// the sharpness tests need
+pub fn checkerboard(width: u32, height: u32, cell: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        if (x / cell + y / cell).is_multiple_of(2) {
+            Rgb([64, 64, 64])
+        } else {
+            Rgb([192, 192, 192])
+        }
+    }))
+}
+
+/// FNV-1a hash of the RGBA pixels of an image, for golden assertions that
+/// don't care about how the pixels were encoded. Golden values change when
+/// the pixels do (like after changing the resizing or a libwebp upgrade, since
+/// webp is lossy), not when only the container bytes do. When one changes,
+/// look at the output before updating the value.
+pub fn pixel_hash(im: &DynamicImage) -> u64 {
+    crate::util::fnv1a(im.to_rgba8().as_raw())
+}
+
+/// The image as a PNG file, like a client would upload it
+pub fn png_bytes(im: &DynamicImage) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    im.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .unwrap();
+    bytes
+}
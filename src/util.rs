@@ -1,7 +1,9 @@
 //! Useful things that aren't entirely specific to this project.
 
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use image::ImageFormat;
-use mongodb::bson::Bson;
+use mongodb::bson::{Bson, Document};
 use rand::Rng;
 use std::fmt;
 
@@ -45,21 +47,13 @@ impl fmt::Display for ImageId {
     }
 }
 
+/// The characters generated ids are made of. There are no vowels so ids
+/// don't spell words.
+pub const ID_ALPHABET: &[u8] = b"bcdfghjklmnpqrstvwxyzBCDFGHJKLMNPQRSTVWXYZ0123456789-_";
+
 /// Generate a random string meant to be used as an id.
 pub fn generate_random_id(length: usize) -> ImageId {
-    ImageId(generate_random_string(
-        length,
-        b"bcdfghjklmnpqrstvwxyzBCDFGHJKLMNPQRSTVWXYZ0123456789-_",
-    ))
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    #[test]
-    fn generate_random_id_works() {
-        assert_eq!(generate_random_id(5).0.len(), 5);
-    }
+    ImageId(generate_random_string(length, ID_ALPHABET))
 }
 
 /// Convert a string mime type to an `ImageFormat`, default to Jpeg if not found.
@@ -82,3 +76,256 @@ pub fn mimetype_to_format(mimetype: &str) -> ImageFormat {
         _ => ImageFormat::Jpeg,
     }
 }
+
+/// Parse a loosely formatted boolean from a form field, anything that isn't
+/// recognized as true is false.
+pub fn parse_bool_field(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "true" | "1" | "on" | "yes"
+    )
+}
+
+/// Get the domain from a Referer header, or None if it's missing, invalid or
+/// the same as `own_host`.
+pub fn referrer_domain(referer: &str, own_host: &str) -> Option<String> {
+    let url = reqwest::Url::parse(referer).ok()?;
+    let domain = url
+        .host_str()?
+        .trim_start_matches("www.")
+        .to_ascii_lowercase();
+    let own_domain = own_host.split(':').next().unwrap_or(own_host);
+    if domain.is_empty() || domain.eq_ignore_ascii_case(own_domain) {
+        return None;
+    }
+    Some(domain)
+}
+
+/// Hash a password with Argon2 and a random salt
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Check a password against a hash from `hash_password`
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// 64 bit FNV-1a, a simple hash that unlike std's hasher is the same on every
+/// run and platform
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Whether an If-None-Match header value matches an ETag, using the weak
+/// comparison that RFC 9110 asks for
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// The licenses that can be picked by name, anything else has to be a URL
+const LICENSES: [&str; 8] = [
+    "all-rights-reserved",
+    "CC0-1.0",
+    "CC-BY-4.0",
+    "CC-BY-SA-4.0",
+    "CC-BY-NC-4.0",
+    "CC-BY-ND-4.0",
+    "CC-BY-NC-SA-4.0",
+    "CC-BY-NC-ND-4.0",
+];
+
+/// Normalize a license chosen by an uploader, either one of `LICENSES`
+/// (case insensitive) or an http(s) URL to a custom license. Returns None if
+/// it's neither.
+pub fn parse_license(value: &str) -> Option<String> {
+    let value = value.trim();
+    if let Some(license) = LICENSES
+        .iter()
+        .find(|license| license.eq_ignore_ascii_case(value))
+    {
+        return Some(license.to_string());
+    }
+    let is_url = (value.starts_with("https://") || value.starts_with("http://"))
+        && value.len() <= 512
+        && !value.chars().any(|c| c.is_whitespace() || c.is_control());
+    is_url.then(|| value.to_string())
+}
+
+/// Why binary data couldn't be read from a document
+#[derive(Debug, PartialEq)]
+pub enum StoredDataError {
+    /// The field isn't there, like in documents from before it was added
+    Missing,
+    /// The field isn't binary or is empty, or its content type is unknown
+    Corrupt,
+}
+
+/// Get a binary field and its content type from a document. If the content
+/// type is missing it's guessed from the data.
+pub fn get_stored_data(
+    doc: &Document,
+    data_key: &str,
+    content_type_key: &str,
+) -> Result<(Vec<u8>, String), StoredDataError> {
+    let data = match doc.get(data_key) {
+        None | Some(Bson::Null) => return Err(StoredDataError::Missing),
+        Some(Bson::Binary(binary)) if !binary.bytes.is_empty() => binary.bytes.clone(),
+        Some(_) => return Err(StoredDataError::Corrupt),
+    };
+    let content_type = match doc.get_str(content_type_key) {
+        Ok(content_type) if !content_type.is_empty() => content_type.to_string(),
+        _ => infer::get(&data)
+            .filter(|kind| kind.matcher_type() == infer::MatcherType::Image)
+            .ok_or(StoredDataError::Corrupt)?
+            .mime_type()
+            .to_string(),
+    };
+    Ok((data, content_type))
+}
+
+/// Get an integer field from a document whether it was stored as an i32 or i64
+pub fn get_int(doc: &Document, key: &str) -> Option<i64> {
+    match doc.get(key)? {
+        Bson::Int32(v) => Some(*v as i64),
+        Bson::Int64(v) => Some(*v),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mongodb::bson::{spec::BinarySubtype, Binary};
+    #[test]
+    fn hash_password_roundtrip() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+        assert!(!verify_password("hunter2", "not a hash"));
+    }
+    #[test]
+    fn referrer_domain_works() {
+        assert_eq!(
+            referrer_domain("https://www.example.com/page?q=1", "i.dishis.tech"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            referrer_domain("https://i.dishis.tech/", "i.dishis.tech"),
+            None
+        );
+        assert_eq!(
+            referrer_domain("http://localhost:8000/", "localhost:8000"),
+            None
+        );
+        assert_eq!(referrer_domain("not a url", "i.dishis.tech"), None);
+    }
+    #[test]
+    fn parse_bool_field_works() {
+        assert!(parse_bool_field("true"));
+        assert!(parse_bool_field(" On "));
+        assert!(!parse_bool_field("false"));
+        assert!(!parse_bool_field(""));
+    }
+    #[test]
+    fn fnv1a_works() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+    #[test]
+    fn etag_matches_works() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\"", "\"abc\""));
+        assert!(!etag_matches("", "\"abc\""));
+    }
+    #[test]
+    fn parse_license_works() {
+        assert_eq!(parse_license("cc-by-4.0"), Some("CC-BY-4.0".to_string()));
+        assert_eq!(
+            parse_license(" https://example.com/license "),
+            Some("https://example.com/license".to_string())
+        );
+        assert_eq!(parse_license("CC-BY-5.0"), None);
+        assert_eq!(parse_license("https://example.com/a\r\nX-Evil: 1"), None);
+        assert_eq!(parse_license("javascript:alert(1)"), None);
+    }
+    #[test]
+    fn get_stored_data_works() {
+        let doc = mongodb::bson::doc! {
+            "data": Binary { subtype: BinarySubtype::Generic, bytes: vec![1, 2, 3] },
+            "content_type": "image/webp",
+        };
+        assert_eq!(
+            get_stored_data(&doc, "data", "content_type"),
+            Ok((vec![1, 2, 3], "image/webp".to_string()))
+        );
+    }
+    #[test]
+    fn get_stored_data_missing_fields() {
+        let doc = mongodb::bson::doc! { "data": null, "content_type": "image/webp" };
+        assert_eq!(
+            get_stored_data(&doc, "data", "content_type"),
+            Err(StoredDataError::Missing)
+        );
+        assert_eq!(
+            get_stored_data(&doc, "thumbnail_data", "thumbnail_content_type"),
+            Err(StoredDataError::Missing)
+        );
+    }
+    #[test]
+    fn get_stored_data_corrupt_fields() {
+        let wrong_type = mongodb::bson::doc! { "data": "not binary", "content_type": "image/png" };
+        let empty = mongodb::bson::doc! {
+            "data": Binary { subtype: BinarySubtype::Generic, bytes: vec![] },
+            "content_type": "image/png",
+        };
+        let unknown_content_type = mongodb::bson::doc! {
+            "data": Binary { subtype: BinarySubtype::Generic, bytes: vec![1, 2, 3] },
+        };
+        for doc in [wrong_type, empty, unknown_content_type] {
+            assert_eq!(
+                get_stored_data(&doc, "data", "content_type"),
+                Err(StoredDataError::Corrupt)
+            );
+        }
+    }
+    #[test]
+    fn get_stored_data_guesses_content_type() {
+        let doc = mongodb::bson::doc! {
+            "data": Binary { subtype: BinarySubtype::Generic, bytes: b"GIF89a".to_vec() },
+            "content_type": 5,
+        };
+        assert_eq!(
+            get_stored_data(&doc, "data", "content_type"),
+            Ok((b"GIF89a".to_vec(), "image/gif".to_string()))
+        );
+    }
+    #[test]
+    fn generate_random_id_works() {
+        assert_eq!(generate_random_id(5).0.len(), 5);
+    }
+    #[test]
+    fn generated_ids_use_id_alphabet() {
+        let id = generate_random_id(64);
+        assert!(id.0.bytes().all(|c| ID_ALPHABET.contains(&c)));
+        // the canary relies on this to never clash with a real image
+        assert!(!ID_ALPHABET.contains(&b'.'));
+    }
+}